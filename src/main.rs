@@ -1,14 +1,30 @@
 #![allow(unused_imports, dead_code)]
 
 mod time_keeper {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
     use std::time::{Instant, Duration};
 
+    /// 間引きチェックで実際に時刻を確認する目安の間隔
+    const CHECK_PERIOD: Duration = Duration::from_micros(100);
+
+    /// ターンをまたいで変化する値はatomicに持ち、`Arc`で複数スレッドから共有できるようにしている
     pub struct TimeKeeper {
         start_time: Instant,
-        before_time: Instant,
+        /// ターン開始時刻(start_timeからの経過nsec)
+        before_time: AtomicU64,
         time_threshold: Duration,
         end_turn: i64,
-        turn: i64,
+        turn: AtomicI64,
+        check_interval: AtomicUsize,
+        next_check_iter: AtomicUsize,
+        /// 最後に実際の時刻を確認した時刻(start_timeからの経過nsec)
+        last_check_time: AtomicU64,
+        time_over: AtomicBool,
+        /// 毎ターンの入出力に見込む時間
+        io_reserve: Duration,
+        /// `record_turn_time`で記録した各ターンの所要時間
+        turn_times: Vec<Duration>,
     }
 
     impl TimeKeeper {
@@ -18,32 +34,194 @@ mod time_keeper {
         pub fn new(time_threshold: u64, end_turn: i64) -> Self {
             Self {
                 start_time: Instant::now(),
-                before_time: Instant::now(),
+                before_time: AtomicU64::new(0),
                 time_threshold: Duration::from_millis(time_threshold),
                 end_turn,
-                turn: 0,
+                turn: AtomicI64::new(0),
+                check_interval: AtomicUsize::new(1),
+                next_check_iter: AtomicUsize::new(0),
+                last_check_time: AtomicU64::new(0),
+                time_over: AtomicBool::new(false),
+                io_reserve: Duration::ZERO,
+                turn_times: vec![],
             }
         }
 
+        /// 毎ターンの入出力(`pt`の読み込みと行動の出力)に見込む時間を設定する
+        /// 各ターンの制限時間からこの分を差し引いて探索に使う
+        pub fn set_expected_io_ms(&mut self, ms: u64) {
+            self.io_reserve = Duration::from_millis(ms);
+        }
+
+        /// ターンの終わりに呼び、`set_turn`からの経過時間をそのターンの所要時間として記録する
+        pub fn record_turn_time(&mut self) {
+            let before_time = self.start_time + Duration::from_nanos(*self.before_time.get_mut());
+            self.turn_times.push(Instant::now() - before_time);
+        }
+
+        /// これまでに記録した各ターンの所要時間(記録順)。締め切り間際の外れ値を探すのに使う
+        pub fn turn_time_histogram(&self) -> Vec<Duration> {
+            self.turn_times.clone()
+        }
+
+        /// 現在のターンに割り振られた制限時間
+        pub fn turn_threshold(&self) -> Duration {
+            self.turn_threshold_at(Instant::now())
+        }
+
+        /// 複数スレッドで同じ締め切りを参照するために`Arc`で包む
+        pub fn shared(self) -> Arc<TimeKeeper> {
+            Arc::new(self)
+        }
+
         /// ターンとターン開始時間を更新する
-        pub fn set_turn(&mut self, turn: i64) {
-            self.turn = turn;
-            self.before_time = Instant::now();
+        pub fn set_turn(&self, turn: i64) {
+            let now = self.elapsed_nanos(Instant::now());
+            self.turn.store(turn, Ordering::Relaxed);
+            self.before_time.store(now, Ordering::Relaxed);
+            self.check_interval.store(1, Ordering::Relaxed);
+            self.next_check_iter.store(0, Ordering::Relaxed);
+            self.last_check_time.store(now, Ordering::Relaxed);
+            self.time_over.store(false, Ordering::Relaxed);
         }
 
         /// 各ターンに割り振られた制限時間を超過したか判定
         pub fn is_time_over(&self) -> bool {
+            self.is_time_over_at(Instant::now())
+        }
+
+        /// `is_time_over`の間引き版。`iter`はターン内の反復回数
+        /// 実際に時刻を確認するのは`check_interval`回に1回だけで、それ以外は前回の判定結果を返す。
+        /// 間隔は直近の反復速度から、およそ`CHECK_PERIOD`ごとに時刻を確認するように調整する
+        pub fn is_time_over_throttled(&mut self, iter: usize) -> bool {
+            let time_over = *self.time_over.get_mut();
+            if time_over || iter < *self.next_check_iter.get_mut() {
+                return time_over;
+            }
             let now = Instant::now();
+            let time_over = self.is_time_over_at(now);
+            let now = self.elapsed_nanos(now);
+            let elapsed = Duration::from_nanos(now - *self.last_check_time.get_mut());
+            let check_interval = self.check_interval.get_mut();
+            if elapsed < CHECK_PERIOD {
+                *check_interval *= 2;
+            } else if elapsed > CHECK_PERIOD * 2 {
+                *check_interval = (*check_interval / 2).max(1);
+            }
+            *self.next_check_iter.get_mut() = iter + *check_interval;
+            *self.last_check_time.get_mut() = now;
+            *self.time_over.get_mut() = time_over;
+            time_over
+        }
+
+        fn is_time_over_at(&self, now: Instant) -> bool {
+            let before_time = self.start_time + Duration::from_nanos(self.before_time.load(Ordering::Relaxed));
+            let last_diff = now - before_time;
+            last_diff >= self.turn_threshold_at(now)
+        }
+
+        fn turn_threshold_at(&self, now: Instant) -> Duration {
+            let turn = self.turn.load(Ordering::Relaxed);
             let whole_diff = now - self.start_time;
-            let last_diff = now - self.before_time;
-            let remaining_time = self.time_threshold - whole_diff;
-            let now_threshold = remaining_time / (self.end_turn - self.turn) as u32;
-            last_diff >= now_threshold
+            let remaining_time = self.time_threshold.saturating_sub(whole_diff);
+            let now_threshold = remaining_time / (self.end_turn - turn).max(1) as u32;
+            now_threshold.saturating_sub(self.io_reserve)
+        }
+
+        fn elapsed_nanos(&self, now: Instant) -> u64 {
+            (now - self.start_time).as_nanos() as u64
+        }
+    }
+
+    /// ターン`turn`の探索にかかった時間`elapsed`が制限時間`budget`を`tolerance`より多く超えていれば警告文を返す
+    pub fn overshoot_warning(turn: i64, elapsed: Duration, budget: Duration, tolerance: Duration) -> Option<String> {
+        let overshoot = elapsed.checked_sub(budget)?;
+        if overshoot <= tolerance {
+            return None;
+        }
+        Some(format!(
+            "turn {}: search took {:.3}ms, exceeded the budget {:.3}ms by {:.3}ms",
+            turn,
+            elapsed.as_secs_f64() * 1000.,
+            budget.as_secs_f64() * 1000.,
+            overshoot.as_secs_f64() * 1000.,
+        ))
+    }
+}
+
+mod profile {
+    use std::time::Duration;
+    #[cfg(feature = "profiling")]
+    use std::sync::atomic::{AtomicU64, Ordering};
+    #[cfg(feature = "profiling")]
+    use std::time::Instant;
+
+    /// 計測する処理の種類
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Phase {
+        /// 傾けて重力を適用する(`State::advance`)
+        Advance,
+        /// スコアの計算(`State::get_score`)
+        Scoring,
+        /// 配置位置の取得とキャンディの配置(`State::update`, `State::simulation_update`)
+        Placement,
+    }
+
+    #[cfg(feature = "profiling")]
+    static ELAPSED_NANOS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+    /// 処理ごとの累計時間。`profiling`featureが無効なら常に0
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct ProfileReport {
+        pub advance: Duration,
+        pub scoring: Duration,
+        pub placement: Duration,
+    }
+
+    impl ProfileReport {
+        pub fn total(&self) -> Duration {
+            self.advance + self.scoring + self.placement
+        }
+    }
+
+    /// `f`を実行し、`profiling`featureが有効ならかかった時間を`phase`に足す
+    #[inline(always)]
+    pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "profiling")]
+        {
+            let start = Instant::now();
+            let result = f();
+            ELAPSED_NANOS[phase as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            result
+        }
+        #[cfg(not(feature = "profiling"))]
+        {
+            let _ = phase;
+            f()
+        }
+    }
+
+    /// これまでの累計時間
+    pub fn report() -> ProfileReport {
+        #[cfg(feature = "profiling")]
+        {
+            let load = |phase: Phase| Duration::from_nanos(ELAPSED_NANOS[phase as usize].load(Ordering::Relaxed));
+            ProfileReport { advance: load(Phase::Advance), scoring: load(Phase::Scoring), placement: load(Phase::Placement) }
+        }
+        #[cfg(not(feature = "profiling"))]
+        ProfileReport::default()
+    }
+
+    /// 累計時間を0に戻す
+    pub fn reset() {
+        #[cfg(feature = "profiling")]
+        for nanos in ELAPSED_NANOS.iter() {
+            nanos.store(0, Ordering::Relaxed);
         }
     }
 }
 
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use once_cell::sync::Lazy;
 
 use proconio::input;
@@ -58,19 +236,159 @@ use time_keeper::TimeKeeper;
 const H: usize = 10;
 const W: usize = 10;
 const END_TURN: i64 = 100;
+const COLORS: usize = 3;
 
 pub static FUTURE_CANDIES: Lazy<Mutex<[u8; END_TURN as usize]>> = Lazy::new(|| Mutex::new([0u8; END_TURN as usize]));
 pub static RAND_FOR_ACTION: Lazy<Mutex<StdRng>> = Lazy::new(|| {
     Mutex::new(StdRng::seed_from_u64(80))
 });
 
+/// `RAND_FOR_ACTION`の現在の状態を写し取る。`rng_restore`に渡すと、その後に引いた乱数をなかったことにできる
+pub fn rng_checkpoint() -> StdRng {
+    RAND_FOR_ACTION.lock().unwrap().clone()
+}
+
+/// `RAND_FOR_ACTION`を`rng_checkpoint`で写し取った状態に戻す
+pub fn rng_restore(state: StdRng) {
+    *RAND_FOR_ACTION.lock().unwrap() = state;
+}
+
 const SIMULATION_MAX: usize = 14000;
 pub static RANDOM_FOR_SIMULATION: Lazy<Mutex<Vec<Vec<i64>>>> = Lazy::new(|| {
-    Mutex::new(vec![vec![0i64; END_TURN as usize]; SIMULATION_MAX])
+    Mutex::new(generate_simulation_table(TableStrategy::Independent))
+});
+
+/// シミュレーション用乱数表の作り方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableStrategy {
+    /// 各値を独立に一様乱数で決める
+    Independent,
+    /// ターンごとに`1..=残りターン数`の各値がほぼ同じ回数ずつ現れるように層化し、行の順番をシャッフルする(ラテン超方格法)
+    /// 独立な乱数だと偶然の偏りで特定の配置が多く出ることがあるが、層化すると各ターンの配置が均等に散らばり、
+    /// モンテカルロの評価値の分散が小さくなる
+    Stratified,
+}
+
+/// シミュレーション用乱数表を作り直す
+pub fn fill_simulation_table(strategy: TableStrategy) {
+    *RANDOM_FOR_SIMULATION.lock().unwrap() = generate_simulation_table(strategy);
+}
+
+/// シミュレーション用乱数表をテキスト(1行に1行分、空白区切り)で書き出す。別の環境で`load_simulation_table`すれば同じ表で再現できる
+pub fn dump_simulation_table(path: &std::path::Path) -> std::io::Result<()> {
+    let table = RANDOM_FOR_SIMULATION.lock().unwrap();
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for row in table.iter() {
+        let line: Vec<String> = row.iter().map(|p| p.to_string()).collect();
+        writeln!(out, "{}", line.join(" "))?;
+    }
+    out.flush()
+}
+
+/// `dump_simulation_table`で書き出した表を読み込んでシミュレーション用乱数表を置き換える
+/// 行数が`SIMULATION_MAX`、各行が`END_TURN`個で、ターン`t`の値が`1..=END_TURN - t`に収まっているか検証する
+pub fn load_simulation_table(path: &std::path::Path) -> std::io::Result<()> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+    let text = std::fs::read_to_string(path)?;
+    let mut table = Vec::with_capacity(SIMULATION_MAX);
+    for (i, line) in text.lines().enumerate() {
+        let mut row = Vec::with_capacity(END_TURN as usize);
+        for (turn, token) in line.split_whitespace().enumerate() {
+            let p = token.parse::<i64>().map_err(|_| invalid(format!("row {} has a non-number {:?}", i, token)))?;
+            if !(1..=END_TURN - turn as i64).contains(&p) {
+                return Err(invalid(format!("row {} turn {} must be in 1..={}, got {}", i, turn, END_TURN - turn as i64, p)));
+            }
+            row.push(p);
+        }
+        if row.len() != END_TURN as usize {
+            return Err(invalid(format!("row {} must have {} values, got {}", i, END_TURN, row.len())));
+        }
+        table.push(row);
+    }
+    if table.len() != SIMULATION_MAX {
+        return Err(invalid(format!("expected {} rows, got {}", SIMULATION_MAX, table.len())));
+    }
+    *RANDOM_FOR_SIMULATION.lock().unwrap() = table;
+    Ok(())
+}
+
+fn generate_simulation_table(strategy: TableStrategy) -> Vec<Vec<i64>> {
+    let mut rand_for_simulation = vec![vec![0i64; END_TURN as usize]; SIMULATION_MAX];
+    let mut rng = StdRng::seed_from_u64(0);
+    match strategy {
+        TableStrategy::Independent => {
+            for row in rand_for_simulation.iter_mut() {
+                for (turn, p) in row.iter_mut().enumerate() {
+                    let remain_turn = END_TURN - turn as i64;
+                    *p = rng.gen_range(1..=remain_turn);
+                }
+            }
+        },
+        TableStrategy::Stratified => {
+            for turn in 0..END_TURN as usize {
+                let remain_turn = END_TURN as usize - turn;
+                let mut column: Vec<i64> = (0..SIMULATION_MAX).map(|i| (i * remain_turn / SIMULATION_MAX) as i64 + 1).collect();
+                column.shuffle(&mut rng);
+                for (row, p) in rand_for_simulation.iter_mut().zip(column) {
+                    row[turn] = p;
+                }
+            }
+        }
+    }
+    rand_for_simulation
+}
+
+pub static ZOBRIST: Lazy<[[[u64; COLORS + 1]; W]; H]> = Lazy::new(|| {
+    let mut rng = StdRng::seed_from_u64(15);
+    let mut table = [[[0u64; COLORS + 1]; W]; H];
+    for row in table.iter_mut() {
+        for cell in row.iter_mut() {
+            for hash in cell.iter_mut() {
+                *hash = rng.gen_range(0..=u64::MAX);
+            }
+        }
+    }
+    table
 });
+/// 各色の個数が`counts[色]`のとき、各色がそれぞれ1つの連結成分にまとまった場合のスコア(`counts[0]`は無視する)
+pub fn max_possible_score(counts: &[i64; COLORS + 1]) -> f64 {
+    counts[1..].iter().map(|c| (c * c) as f64).sum()
+}
+
+/// 未来のキャンディの色がちょうど`END_TURN`個あり、どれも`1..=COLORS`に収まっているか確かめる
+/// 短い入力をそのまま使うと、足りないターンの色が0として扱われてスコアやルールベースが壊れる
+pub fn validate_future(future: &[u8]) -> Result<(), String> {
+    if future.len() != END_TURN as usize {
+        return Err(format!("future must have {} colors, got {}", END_TURN, future.len()));
+    }
+    for (t, &candy) in future.iter().enumerate() {
+        if !(1..=COLORS as u8).contains(&candy) {
+            return Err(format!("future[{}] must be in 1..={}, got {}", t, COLORS, candy));
+        }
+    }
+    Ok(())
+}
+
+/// 未来のキャンディの色を設定する。`validate_future`を通らなければ何も変えずにエラーを返す
+pub fn set_future(future: &[u8]) -> Result<(), String> {
+    validate_future(future)?;
+    let mut candies = FUTURE_CANDIES.lock().unwrap();
+    for (t, &f) in future.iter().enumerate() {
+        candies[t] = f;
+    }
+    Ok(())
+}
 
+/// 公式の入力生成と同じ規則で各ターンの配置位置を生成する
+/// ターン`t`(0-indexed)の開始時点で空きマスはちょうど`END_TURN - t`個あり、
+/// その中から1-indexedの番号`1..=END_TURN - t`を一様ランダムに選ぶ。
+/// 分布は公式と一致するが、乱数列そのものは`StdRng`によるもので公式のseedとは対応しない
+pub fn official_placement_stream(seed: u64) -> impl Iterator<Item = i64> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..END_TURN).map(move |turn| rng.gen_range(1..=END_TURN - turn))
+}
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Action {
     Forward,
     Back,
@@ -78,6 +396,38 @@ pub enum Action {
     Right,
 }
 
+impl Action {
+    /// `LEGAL_ACTIONS`での添字
+    pub fn to_index(self) -> usize {
+        match self {
+            Action::Forward => 0,
+            Action::Back => 1,
+            Action::Left => 2,
+            Action::Right => 3,
+        }
+    }
+
+    /// `to_index`の逆。範囲外なら`None`
+    pub fn from_index(i: usize) -> Option<Action> {
+        LEGAL_ACTIONS.get(i).copied()
+    }
+
+    /// 上下方向に傾ける行動か
+    pub fn is_vertical(self) -> bool {
+        matches!(self, Action::Forward | Action::Back)
+    }
+
+    /// 逆向きの行動
+    pub fn opposite(self) -> Action {
+        match self {
+            Action::Forward => Action::Back,
+            Action::Back => Action::Forward,
+            Action::Left => Action::Right,
+            Action::Right => Action::Left,
+        }
+    }
+}
+
 pub fn action_to_char(action: Action) -> char {
     match action {
         Action::Forward => 'F',
@@ -87,247 +437,4824 @@ pub fn action_to_char(action: Action) -> char {
     }
 }
 
+/// 行動を書き出した後にflushする頻度
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputPolicy {
+    /// 毎ターンflushする。対話型ジャッジでは必須
+    FlushEachTurn,
+    /// `finish`までflushしない
+    FlushAtEnd,
+    /// `n`行書くごとにflushする。`n`が0なら`FlushAtEnd`と同じ
+    FlushEvery(usize),
+}
+
+/// 行動の出力先
+/// 対話型ジャッジは次の入力を返す前に出力を待つので、対話時は毎ターンflushしないとデッドロックする。
+/// オフライン実行では`finish`までまとめて書き出せばよい
+pub struct ActionWriter<O: Write> {
+    out: O,
+    policy: OutputPolicy,
+    written: usize,
+}
+
+impl<O: Write> ActionWriter<O> {
+    pub fn with_policy(out: O, policy: OutputPolicy) -> Self {
+        Self { out, policy, written: 0 }
+    }
+
+    /// 毎ターンflushする(対話型ジャッジ用)
+    pub fn interactive(out: O) -> Self {
+        Self::with_policy(out, OutputPolicy::FlushEachTurn)
+    }
+
+    /// `finish`までflushしない(オフライン実行用)。`out`は`BufWriter`などで包んでおくとよい
+    pub fn offline(out: O) -> Self {
+        Self::with_policy(out, OutputPolicy::FlushAtEnd)
+    }
+
+    pub fn policy(&self) -> OutputPolicy {
+        self.policy
+    }
+
+    pub fn write_action(&mut self, action: Action) -> std::io::Result<()> {
+        writeln!(self.out, "{}", action_to_char(action))?;
+        self.written += 1;
+        let flush = match self.policy {
+            OutputPolicy::FlushEachTurn => true,
+            OutputPolicy::FlushAtEnd => false,
+            OutputPolicy::FlushEvery(n) => n > 0 && self.written.is_multiple_of(n),
+        };
+        if flush {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// 残りを書き出して出力先を返す
+    pub fn finish(mut self) -> std::io::Result<O> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// `State::suggest_direction_change`で見る直近の行動の数
+pub const DIRECTION_CHANGE_WINDOW: usize = 3;
+
+/// 盤面の4隅。左上、右上、左下、右下の順
+pub const CORNERS: [(usize, usize); 4] = [(0, 0), (0, W - 1), (H - 1, 0), (H - 1, W - 1)];
+
+/// `State::features`の長さ
+pub const FEATURE_LEN: usize = COLORS + 4 + CORNERS.len() * COLORS;
+
 #[derive(Clone)]
 pub struct State {
     board: [[u8; W]; H],
     turn: i64,
+    last_placement: Option<(usize, usize)>,
+    /// 最後に傾けた方向
+    last_action: Option<Action>,
+    /// 各マスのキャンディが置かれたターン(解析用)。傾けるとキャンディと一緒に移動する
+    /// -1は空きマス、または`place`を通さずに置かれたキャンディ
+    placed_turn: [[i16; W]; H],
+    /// 動かない壁のマス(変種ルールの実験用)。壁にはキャンディが置かれず、傾けたときのキャンディは壁の手前で止まる
+    walls: [[bool; W]; H],
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
-        Self { board: [[0u8; W]; H], turn: 0i64 }
+        Self {
+            board: [[0u8; W]; H],
+            turn: 0i64,
+            last_placement: None,
+            last_action: None,
+            placed_turn: [[-1; W]; H],
+            walls: [[false; W]; H],
+        }
+    }
+
+    /// マス`(y, x)`を壁にするか、壁を取り除く。壁にするマスは空でなければならない
+    pub fn set_wall(&mut self, y: usize, x: usize, wall: bool) {
+        debug_assert!(!wall || self.board[y][x] == 0);
+        self.walls[y][x] = wall;
+    }
+
+    pub fn is_wall(&self, y: usize, x: usize) -> bool {
+        self.walls[y][x]
+    }
+
+    /// 盤面を行ごとの配列として書き出す(外部ツールでの解析用)
+    pub fn to_rows(&self) -> Vec<Vec<u8>> {
+        self.board.iter().map(|row| row.to_vec()).collect()
+    }
+
+    /// 行ごとの配列から状態を作る。大きさが`H`×`W`で、各値が`0..=COLORS`に収まっているか検証する
+    pub fn from_rows(rows: &[Vec<u8>], turn: i64) -> Result<State, String> {
+        if rows.len() != H {
+            return Err(format!("expected {} rows, got {}", H, rows.len()));
+        }
+        let mut state = State::new();
+        for (y, row) in rows.iter().enumerate() {
+            if row.len() != W {
+                return Err(format!("row {} must have {} cells, got {}", y, W, row.len()));
+            }
+            state.board[y].copy_from_slice(row);
+        }
+        state.turn = turn;
+        state.check_invariants()?;
+        Ok(state)
+    }
+
+    /// 初期状態に戻す
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// 最後にキャンディが置かれたマス(傾ける前の位置)
+    pub fn last_placement(&self) -> Option<(usize, usize)> {
+        self.last_placement
+    }
+
+    /// 最後に傾けた方向
+    pub fn last_action(&self) -> Option<Action> {
+        self.last_action
+    }
+
+    /// マス`(y, x)`のキャンディが置かれたターン。空きマスや`place`を通さずに置かれたキャンディなら`None`
+    pub fn placed_turn(&self, y: usize, x: usize) -> Option<i64> {
+        let turn = self.placed_turn[y][x];
+        (turn >= 0).then_some(turn as i64)
+    }
+
+    /// マス`a`と`b`の中身(キャンディと置かれたターン)を入れ替える
+    #[inline]
+    fn swap_cells(&mut self, (ay, ax): (usize, usize), (by, bx): (usize, usize)) {
+        (self.board[ay][ax], self.board[by][bx]) = (self.board[by][bx], self.board[ay][ax]);
+        (self.placed_turn[ay][ax], self.placed_turn[by][bx]) = (self.placed_turn[by][bx], self.placed_turn[ay][ax]);
     }
 
     pub fn is_done(&self) -> bool {
         self.turn >= END_TURN
     }
 
+    pub fn turn(&self) -> i64 {
+        self.turn
+    }
+
+    /// 残りターン数
+    pub fn remaining_turns(&self) -> i64 {
+        END_TURN - self.turn
+    }
+
+    /// 盤面が埋まるまでのターン数。1ターンに1個置かれるので空きマスの数に等しい
+    /// 通常のゲームでは`remaining_turns`と一致するが、途中から再開した盤面ではずれることがある
+    pub fn turns_until_full(&self) -> i64 {
+        self.empty_count() as i64
+    }
+
     pub fn advance(&mut self, action: Action) {
+        #[cfg(debug_assertions)]
+        let before = self.clone();
+        profile::measure(profile::Phase::Advance, || self.tilt(action));
+        self.last_action = Some(action);
+        self.turn += 1;
+        debug_assert_eq!(self.check_board_invariants(), Ok(()));
+        #[cfg(debug_assertions)]
+        self.assert_conservation(&before);
+    }
+
+    /// 盤面を`action`の向きに傾ける。ターンや最後の行動は変えない
+    fn tilt(&mut self, action: Action) {
         match action {
-            Action::Forward => {
-                for x in 0..W {
-                    let mut dest = 0usize;
-                    for y in 0..H {
-                        if self.board[y][x] == 0 {
-                            continue;
-                        }
-                        (self.board[y][x], self.board[dest][x]) = (self.board[dest][x], self.board[y][x]);
-                        dest += 1;
-                    }
+            Action::Forward => (0..W).for_each(|x| self.compact_column(x, true)),
+            Action::Back => (0..W).for_each(|x| self.compact_column(x, false)),
+            Action::Left => (0..H).for_each(|y| self.compact_row(y, true)),
+            Action::Right => (0..H).for_each(|y| self.compact_row(y, false)),
+        }
+    }
+
+    /// 傾ける前の状態`before`と色ごとのキャンディの個数が同じであることを確かめる。重力はキャンディを増やしも減らしもしない
+    pub fn assert_conservation(&self, before: &State) {
+        assert_eq!(self.color_counts(), before.color_counts(), "candy counts changed by a tilt");
+    }
+
+    /// 列`x`のキャンディだけを上(`toward_top`がfalseなら下)に詰める。壁は越えない。ターンは進めない
+    pub fn compact_column(&mut self, x: usize, toward_top: bool) {
+        if toward_top {
+            let mut dest = 0usize;
+            for y in 0..H {
+                if self.walls[y][x] {
+                    dest = y + 1;
+                    continue;
                 }
-            },
-            Action::Back => {
-                for x in 0..W {
-                    let mut dest = H - 1;
-                    for y in (0..H).rev() {
-                        if self.board[y][x] == 0 {
-                            continue;
-                        }
-                        (self.board[y][x], self.board[dest][x]) = (self.board[dest][x], self.board[y][x]);
-                        dest -= 1;
-                    }
+                if self.board[y][x] == 0 {
+                    continue;
                 }
-            },
-            Action::Left => {
-                for y in 0..H {
-                    let mut dest = 0;
-                    for x in 0..W {
-                        if self.board[y][x] == 0 {
-                            continue;
-                        }
-                        (self.board[y][x], self.board[y][dest]) = (self.board[y][dest], self.board[y][x]);
-                        dest += 1;
-                    }
+                self.swap_cells((y, x), (dest, x));
+                dest += 1;
+            }
+        } else {
+            let mut dest = H - 1;
+            for y in (0..H).rev() {
+                if self.walls[y][x] {
+                    dest = y.saturating_sub(1);
+                    continue;
                 }
-            },
-            Action::Right => {
-                for y in 0..H {
-                    let mut dest = W - 1;
-                    for x in (0..W).rev() {
-                        if self.board[y][x] == 0 {
-                            continue;
-                        }
-                        (self.board[y][x], self.board[y][dest]) = (self.board[y][dest], self.board[y][x]);
-                        dest -= 1;
-                    }
+                if self.board[y][x] == 0 {
+                    continue;
                 }
+                self.swap_cells((y, x), (dest, x));
+                dest = dest.saturating_sub(1);
             }
         }
-        self.turn += 1;
     }
 
-    pub fn random_update(&mut self) {
-        let remain_turn = END_TURN - self.turn;
-        let p = RAND_FOR_ACTION.lock().unwrap().gen_range(1..=remain_turn);
-        self.update(p);
+    /// 行`y`のキャンディだけを左(`toward_left`がfalseなら右)に詰める。壁は越えない。ターンは進めない
+    pub fn compact_row(&mut self, y: usize, toward_left: bool) {
+        if toward_left {
+            let mut dest = 0;
+            for x in 0..W {
+                if self.walls[y][x] {
+                    dest = x + 1;
+                    continue;
+                }
+                if self.board[y][x] == 0 {
+                    continue;
+                }
+                self.swap_cells((y, x), (y, dest));
+                dest += 1;
+            }
+        } else {
+            let mut dest = W - 1;
+            for x in (0..W).rev() {
+                if self.walls[y][x] {
+                    dest = x.saturating_sub(1);
+                    continue;
+                }
+                if self.board[y][x] == 0 {
+                    continue;
+                }
+                self.swap_cells((y, x), (y, dest));
+                dest = dest.saturating_sub(1);
+            }
+        }
     }
 
-    pub fn simulation_update(&mut self, simulation_cnt: usize) {
-        let p = RANDOM_FOR_SIMULATION.lock().unwrap()[simulation_cnt][self.turn as usize];
-        self.update(p);
+    /// 盤面上の各色のキャンディの個数(添字0は使わない)
+    pub fn color_counts(&self) -> [i64; COLORS + 1] {
+        let mut counts = [0i64; COLORS + 1];
+        for &candy in self.board.iter().flatten() {
+            if candy != 0 {
+                counts[candy as usize] += 1;
+            }
+        }
+        counts
     }
 
-    pub fn update(&mut self, pt: i64) {
-        let mut cnt = 0i64;
-        let candies = FUTURE_CANDIES.lock().unwrap();
+    /// 盤面上で最も多い色。同数なら番号の小さい色、キャンディがなければ0
+    pub fn dominant_color(&self) -> u8 {
+        let counts = self.color_counts();
+        let mut dominant = 0;
+        for candy in 1..=COLORS {
+            if counts[candy] > counts[dominant] {
+                dominant = candy;
+            }
+        }
+        dominant as u8
+    }
+
+    /// 4隅のうち、色`color`のキャンディが最も集まっている隅
+    /// 各キャンディについて隅に近いほど大きい重み(`H + W - 2 - マンハッタン距離`)を足し合わせて比べる
+    pub fn best_corner_for(&self, color: u8) -> (usize, usize) {
+        let mut best_corner = CORNERS[0];
+        let mut best_concentration = -1;
+        for &corner in CORNERS.iter() {
+            let concentration = self.corner_concentration(color, corner);
+            if concentration > best_concentration {
+                best_concentration = concentration;
+                best_corner = corner;
+            }
+        }
+        best_corner
+    }
+
+    /// 色`color`のキャンディの隅`(cy, cx)`への集まり具合。`best_corner_for`を参照
+    fn corner_concentration(&self, color: u8, (cy, cx): (usize, usize)) -> i64 {
+        let mut concentration = 0;
         for y in 0..H {
             for x in 0..W {
-                if self.board[y][x] != 0 {
+                if self.board[y][x] == color {
+                    concentration += (H + W - 2 - y.abs_diff(cy) - x.abs_diff(cx)) as i64;
+                }
+            }
+        }
+        concentration
+    }
+
+    /// 盤面上のキャンディに、まだ置かれていない未来のキャンディ(`future`のうち盤面上の個数番目以降)を加えて、
+    /// 各色が1つの連結成分にまとまった場合のスコア。最後には全てのキャンディが盤面に乗るので、最終スコアの上界になる
+    pub fn remaining_optimal_score(&self, future: &[u8]) -> f64 {
+        let mut counts = self.color_counts();
+        let placed = counts.iter().sum::<i64>() as usize;
+        for &candy in future.iter().skip(placed) {
+            counts[candy as usize] += 1;
+        }
+        max_possible_score(&counts)
+    }
+
+    /// 同じ色のキャンディは、盤面上のものもまだ置かれていない未来のもの(`future`のうち盤面上の個数番目以降)も、
+    /// 合流できる限り1つの連結成分にまとまると仮定したときのスコア。枝刈り用の楽観的な見積もりで厳密な値ではないが、
+    /// 最終スコアの上界になる。キャンディは傾けても壁を越えないので、壁で仕切られた領域(壁以外のマスの上下左右の連結成分)
+    /// をまたいで合流することはない。色ごとに領域内の個数の2乗を足し、未来のキャンディは最も多い領域に全て加える。
+    /// 壁がなければ`remaining_optimal_score`に等しく、壁で仕切られていればそれより小さい
+    pub fn relaxed_upper_bound(&self, future: &[u8]) -> f64 {
+        let mut region_of = [[usize::MAX; W]; H];
+        let mut region_counts: Vec<[i64; COLORS + 1]> = vec![];
+        for sy in 0..H {
+            for sx in 0..W {
+                if self.walls[sy][sx] || region_of[sy][sx] != usize::MAX {
                     continue;
                 }
-                cnt += 1;
-                if cnt == pt {
-                    self.board[y][x] = candies[self.turn as usize];
+                let region = region_counts.len();
+                let mut counts = [0i64; COLORS + 1];
+                region_of[sy][sx] = region;
+                let mut stack = vec![(sy, sx)];
+                while let Some((y, x)) = stack.pop() {
+                    counts[self.board[y][x] as usize] += 1;
+                    let neighbors = [(y.wrapping_sub(1), x), (y + 1, x), (y, x.wrapping_sub(1)), (y, x + 1)];
+                    for (ny, nx) in neighbors {
+                        if ny < H && nx < W && !self.walls[ny][nx] && region_of[ny][nx] == usize::MAX {
+                            region_of[ny][nx] = region;
+                            stack.push((ny, nx));
+                        }
+                    }
                 }
+                region_counts.push(counts);
             }
         }
+        let mut remaining = [0i64; COLORS + 1];
+        let placed = self.color_counts().iter().sum::<i64>() as usize;
+        for &candy in future.iter().skip(placed) {
+            remaining[candy as usize] += 1;
+        }
+        let mut score = 0;
+        for candy in 1..=COLORS {
+            let largest = region_counts.iter().map(|counts| counts[candy]).max().unwrap_or(0);
+            let squares: i64 = region_counts.iter().map(|counts| counts[candy] * counts[candy]).sum();
+            score += squares - largest * largest + (largest + remaining[candy]) * (largest + remaining[candy]);
+        }
+        score as f64
     }
 
-    fn get_group_size(&self, y: usize, x: usize, checked: &mut [[bool; W]; H]) -> i64 {
-        const DX: [isize; 4] = [1, -1, 0, 0];
-        const DY: [isize; 4] = [0, 0, 1, -1];
-        let candy = self.board[y][x];
-        checked[y][x] = true;
+    /// 盤面上の色の偏りを`[0, 1]`で表す。最も多い色の割合を、全色同数なら0、1色だけなら1になるよう正規化したもの
+    /// キャンディがなければ0
+    pub fn color_imbalance(&self) -> f64 {
+        let counts = self.color_counts();
+        let total: i64 = counts.iter().sum();
+        if total == 0 {
+            return 0.;
+        }
+        let max_share = *counts.iter().max().unwrap() as f64 / total as f64;
+        let uniform_share = 1. / COLORS as f64;
+        (max_share - uniform_share) / (1. - uniform_share)
+    }
 
-        let mut q = VecDeque::new();
-        q.push_back((y, x));
-        let mut cnt = 0;
-        while !q.is_empty() {
-            cnt += 1;
-            let (now_y, now_x) = q.pop_front().unwrap();
-            for i in 0..4usize { 
-                let ty = now_y as isize + DY[i];
-                let tx = now_x as isize + DX[i];
+    /// 盤面がどれだけ色ごとにまとまっているかを`[0, 1]`で表す
+    /// 今盤面にあるキャンディだけで達成できる最大スコアに対する現在のスコアの比。キャンディがなければ1
+    pub fn sortedness(&self) -> f64 {
+        let max_score = max_possible_score(&self.color_counts());
+        if max_score == 0. {
+            return 1.;
+        }
+        self.get_score() / max_score
+    }
 
-                if (0..H as isize).contains(&ty) && (0..W as isize).contains(&tx) {
-                    let new_y = ty as usize;
-                    let new_x = tx as usize;
-                    if !checked[new_y][new_x] && self.board[new_y][new_x] == candy {
-                        checked[new_y][new_x] = true;
-                        q.push_back((new_y, new_x));
+    /// 各色が1つの連結成分にまとまった盤面までに必要な傾きの回数の下界(A*などのヒューリスティック用)
+    /// 傾けてもキャンディの数は変わらないので、色ごとの数が`target_counts`(添字0は見ない)と違えば到達できず`f64::INFINITY`。
+    /// 色ごとに、既に1つにまとまっていれば0、そうでなければ1以上とし、全色の最大値を返す。
+    /// 左右に傾けてもキャンディの行は変わらない(上下なら列が変わらない)ことを仮定している。
+    /// そのため、ある色のキャンディのある行の間にその色のない行があり、列にも同じような隙間があれば、
+    /// 1回の傾きではどちらの向きでも隙間の両側がつながらず、2回以上必要になる
+    pub fn heuristic_to_optimal(&self, target_counts: &[i64; COLORS + 1]) -> f64 {
+        if self.color_counts()[1..] != target_counts[1..] {
+            return f64::INFINITY;
+        }
+        let mut group_counts = [0; COLORS + 1];
+        for (color, _) in Scorer::new().groups_with_color(self) {
+            group_counts[color as usize] += 1;
+        }
+        let has_gap = |occupied: &[bool]| match (occupied.iter().position(|&o| o), occupied.iter().rposition(|&o| o)) {
+            (Some(first), Some(last)) => occupied[first..=last].contains(&false),
+            _ => false,
+        };
+        let mut bound = 0;
+        for color in 1..=COLORS as u8 {
+            if group_counts[color as usize] <= 1 {
+                continue;
+            }
+            let mut rows = [false; H];
+            let mut cols = [false; W];
+            for (y, row) in self.board.iter().enumerate() {
+                for (x, &candy) in row.iter().enumerate() {
+                    if candy == color {
+                        rows[y] = true;
+                        cols[x] = true;
                     }
                 }
             }
+            bound = bound.max(if has_gap(&rows) && has_gap(&cols) { 2 } else { 1 });
         }
-        cnt
+        bound as f64
     }
 
-    pub fn get_score(&self) -> f64 {
-        let mut score = 0.;
-        let mut checked = [[false; W]; H];
+    /// ANSIエスケープで色付けした盤面の文字列。1行に1段ずつ出力する
+    pub fn render(&self) -> String {
+        const BACKGROUNDS: [&str; COLORS + 1] = ["", "\x1b[41m", "\x1b[42m", "\x1b[43m"];
+        let mut rendered = String::new();
+        for row in self.board.iter() {
+            for &candy in row.iter() {
+                if candy == 0 {
+                    rendered.push_str(" .");
+                } else {
+                    rendered.push_str(BACKGROUNDS[candy as usize]);
+                    rendered.push_str(&format!("{:>2}", candy));
+                    rendered.push_str("\x1b[0m");
+                }
+            }
+            rendered.push('\n');
+        }
+        rendered
+    }
+
+    /// 状態が満たすべき条件を確認する
+    /// * 各マスの値が`0..=COLORS`に収まっている
+    /// * `turn`が`0..=END_TURN`に収まっている
+    /// * 最後に置いたマスが盤面内を指している
+    /// * 壁のマスにキャンディがない
+    pub fn check_invariants(&self) -> Result<(), String> {
+        self.check_board_invariants()?;
+        if !(0..=END_TURN).contains(&self.turn) {
+            return Err(format!("turn {} is out of 0..={}", self.turn, END_TURN));
+        }
+        Ok(())
+    }
+
+    /// `check_invariants`のうち`turn`以外の条件
+    /// 終局後の盤面を解析のために傾けるとターンは`END_TURN`を超えるので、`advance`ではこちらだけを確かめる
+    fn check_board_invariants(&self) -> Result<(), String> {
         for y in 0..H {
             for x in 0..W {
-                if self.board[y][x] != 0 && !checked[y][x] {
-                    let group_size = self.get_group_size(y, x, &mut checked);
-                    score += (group_size * group_size) as f64;
+                if self.board[y][x] as usize > COLORS {
+                    return Err(format!("cell ({}, {}) has invalid color {}", y, x, self.board[y][x]));
+                }
+                if self.walls[y][x] && self.board[y][x] != 0 {
+                    return Err(format!("wall cell ({}, {}) holds candy {}", y, x, self.board[y][x]));
                 }
             }
         }
-        score
+        if let Some((y, x)) = self.last_placement
+            && (y >= H || x >= W)
+        {
+            return Err(format!("last placement ({}, {}) is out of the board", y, x));
+        }
+        Ok(())
     }
-}
 
-pub const LEGAL_ACTIONS: [Action; 4] = [Action::Forward, Action::Back, Action::Left, Action::Right];
+    /// 行動列を先頭から順に適用する。途中でゲームが終了したらそれ以降は無視する
+    pub fn advance_all(&mut self, actions: &[Action]) {
+        for &action in actions {
+            if self.is_done() {
+                break;
+            }
+            self.advance(action);
+        }
+    }
+
+    /// 傾けるだけ(キャンディの配置なし)で盤面を`target`の盤面にする最短の行動列を、`max_depth`手以内で幅優先探索する
+    /// 実際のゲームでは行動の間にキャンディが置かれるので、重力だけを見た解析用の道具。ターンは進めない
+    pub fn shortest_path_to(&self, target: &State, max_depth: usize) -> Option<Vec<Action>> {
+        if self.board == target.board {
+            return Some(vec![]);
+        }
+        let mut visited = std::collections::HashSet::new();
+        visited.insert(self.board);
+        let mut queue = VecDeque::new();
+        queue.push_back((self.clone(), vec![]));
+        while let Some((state, path)) = queue.pop_front() {
+            if path.len() >= max_depth {
+                continue;
+            }
+            for &action in LEGAL_ACTIONS.iter() {
+                let mut next = state.clone();
+                next.tilt(action);
+                if !visited.insert(next.board) {
+                    continue;
+                }
+                let mut next_path = path.clone();
+                next_path.push(action);
+                if next.board == target.board {
+                    return Some(next_path);
+                }
+                queue.push_back((next, next_path));
+            }
+        }
+        None
+    }
+
+    pub fn random_update(&mut self) {
+        let p = RAND_FOR_ACTION.lock().unwrap().gen_range(1..=self.remaining_turns());
+        self.update(p);
+    }
+
+    pub fn simulation_update(&mut self, simulation_cnt: usize) {
+        let p = profile::measure(profile::Phase::Placement, || {
+            RANDOM_FOR_SIMULATION.lock().unwrap()[simulation_cnt][self.turn as usize]
+        });
+        self.update(p);
+    }
+
+    pub fn update(&mut self, pt: i64) {
+        profile::measure(profile::Phase::Placement, || {
+            let candy = FUTURE_CANDIES.lock().unwrap()[self.turn as usize];
+            // 色0は未来が設定されていないということなので置かない
+            if candy != 0 {
+                self.place(pt, candy);
+            }
+        })
+    }
+
+    /// 空きマス(壁を除く)のうち`pt`番目(1-indexed、行優先)に色`candy`のキャンディを置く
+    pub fn place(&mut self, pt: i64, candy: u8) {
+        let mut cnt = 0i64;
+        for y in 0..H {
+            for x in 0..W {
+                if self.board[y][x] != 0 || self.walls[y][x] {
+                    continue;
+                }
+                cnt += 1;
+                if cnt == pt {
+                    self.board[y][x] = candy;
+                    self.placed_turn[y][x] = self.turn as i16;
+                    self.last_placement = Some((y, x));
+                    debug_assert_eq!(self.check_invariants(), Ok(()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// 空きマス(壁を除く)の数
+    pub fn empty_count(&self) -> usize {
+        self.board.iter().flatten().zip(self.walls.iter().flatten()).filter(|&(&candy, &wall)| candy == 0 && !wall).count()
+    }
+
+    /// 色`color`のキャンディを置いたときのスコアが最大になる空きマス(同値なら行優先で最初のマス)。空きマスがなければ`None`
+    /// 実際のゲームで配置は選べないので、解析や配置モデル用
+    pub fn best_placement_cell(&self, color: u8) -> Option<(usize, usize)> {
+        let mut scorer = Scorer::new();
+        let mut next = self.clone();
+        let mut best: Option<((usize, usize), i64)> = None;
+        for y in 0..H {
+            for x in 0..W {
+                if self.board[y][x] != 0 || self.walls[y][x] {
+                    continue;
+                }
+                next.board[y][x] = color;
+                let score = scorer.score_int(&next);
+                next.board[y][x] = 0;
+                if best.is_none_or(|(_, best_score)| score > best_score) {
+                    best = Some(((y, x), score));
+                }
+            }
+        }
+        best.map(|(cell, _)| cell)
+    }
+
+    /// 次の`k`回の配置で各空きマスが埋まる確率
+    /// 傾けによる移動は考えず、空きマスから一様に選ばれた`k`マス(空きマスの数まで)が埋まるものとする
+    pub fn placement_density(&self, k: usize) -> [[f64; W]; H] {
+        let empties = self.empty_count();
+        if empties == 0 {
+            return [[0.; W]; H];
+        }
+        let prob = k.min(empties) as f64 / empties as f64;
+        std::array::from_fn(|y| std::array::from_fn(|x| if self.board[y][x] == 0 && !self.walls[y][x] { prob } else { 0. }))
+    }
+
+    /// 盤面の中身(キャンディの色と壁かどうか)が異なるマスの数
+    pub fn hamming_distance(&self, other: &State) -> usize {
+        let cells = |state: &State| state.board.iter().flatten().zip(state.walls.iter().flatten()).map(|(&candy, &wall)| (candy, wall)).collect::<Vec<_>>();
+        cells(self).into_iter().zip(cells(other)).filter(|(a, b)| a != b).count()
+    }
+
+    /// 盤面の回転・反転(8通り)の中で最小のZobristハッシュを返す
+    /// 重力は上下左右の4方向すべてで定義されているため、回転・反転した盤面は行動を付け替えれば同じ遷移をする。
+    /// またキャンディは空きマスの中から一様に置かれるので、8通りすべてを同値な局面として扱ってよい(ターン数は含まない)
+    /// 壁も同じように回転・反転する。壁のマスにはキャンディがないので、`ZOBRIST`の色0の値を壁に使う
+    pub fn canonical_hash(&self) -> u64 {
+        const N: usize = H;
+        const _: () = assert!(H == W);
+        let mut hashes = [0u64; 8];
+        for y in 0..H {
+            for x in 0..W {
+                let key = match (self.walls[y][x], self.board[y][x]) {
+                    (true, _) => 0,
+                    (false, 0) => continue,
+                    (false, candy) => candy as usize,
+                };
+                let images = [
+                    (y, x),
+                    (x, N - 1 - y),
+                    (N - 1 - y, N - 1 - x),
+                    (N - 1 - x, y),
+                    (y, N - 1 - x),
+                    (N - 1 - y, x),
+                    (x, y),
+                    (N - 1 - x, N - 1 - y),
+                ];
+                for (hash, (ty, tx)) in hashes.iter_mut().zip(images) {
+                    *hash ^= ZOBRIST[ty][tx][key];
+                }
+            }
+        }
+        hashes.into_iter().min().unwrap()
+    }
+
+    pub fn get_score(&self) -> f64 {
+        profile::measure(profile::Phase::Scoring, || Scorer::new().score(self))
+    }
+
+    /// 行動`action`で傾けた直後の盤面のスコア
+    pub fn score_after(&self, action: Action) -> f64 {
+        let mut state = self.clone();
+        state.advance(action);
+        state.get_score()
+    }
+
+    /// 4方向それぞれに傾けた直後のスコアを`LEGAL_ACTIONS`の順で返す
+    /// 作業用の盤面とScorerを使い回すので、`score_after`を4回呼ぶより軽い
+    pub fn score_all_actions(&self) -> [f64; 4] {
+        let mut scores = [0.; 4];
+        let mut scratch = self.clone();
+        let mut scorer = Scorer::new();
+        for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+            scratch.clone_from(self);
+            scratch.advance(action);
+            scores[d] = scorer.score(&scratch);
+        }
+        scores
+    }
+
+    /// 隣接関係を`connectivity`にしたときのスコア。`Connectivity::Four`なら`get_score`と同じ
+    pub fn get_score_conn(&self, connectivity: Connectivity) -> f64 {
+        Scorer::with_connectivity(connectivity).score(self)
+    }
+
+    /// 整数のままのスコア。`get_score`と同じ値
+    pub fn get_score_int(&self) -> i64 {
+        profile::measure(profile::Phase::Scoring, || Scorer::new().score_int(self))
+    }
+
+    /// 色ごとの連結成分の大きさの2乗和(添字0は未使用)。合計は`get_score`に等しく、どの色がスコアを稼いでいるかが分かる
+    pub fn score_by_color(&self) -> [f64; COLORS + 1] {
+        let mut scores = [0.; COLORS + 1];
+        for (color, size) in Scorer::new().groups_with_color(self) {
+            scores[color as usize] += (size * size) as f64;
+        }
+        scores
+    }
+
+    /// マス`a`と`b`の中身を入れ替えたときのスコア(`self`は変えない)。終局盤面の局所探索などの解析用
+    pub fn score_after_swap(&self, (ay, ax): (usize, usize), (by, bx): (usize, usize)) -> f64 {
+        let mut swapped = self.clone();
+        swapped.board[ay][ax] = self.board[by][bx];
+        swapped.board[by][bx] = self.board[ay][ax];
+        swapped.get_score()
+    }
+
+    /// 空きマス(壁を除く)の上下左右の連結成分を、行優先で最初のマスの順に列挙する
+    pub fn empty_regions(&self) -> Vec<Vec<(usize, usize)>> {
+        let mut checked = [[false; W]; H];
+        let mut regions = vec![];
+        for sy in 0..H {
+            for sx in 0..W {
+                if checked[sy][sx] || self.board[sy][sx] != 0 || self.walls[sy][sx] {
+                    continue;
+                }
+                checked[sy][sx] = true;
+                let mut region = vec![];
+                let mut stack = vec![(sy, sx)];
+                while let Some((y, x)) = stack.pop() {
+                    region.push((y, x));
+                    let neighbors = [(y.wrapping_sub(1), x), (y + 1, x), (y, x.wrapping_sub(1)), (y, x + 1)];
+                    for (ny, nx) in neighbors {
+                        if ny < H && nx < W && !checked[ny][nx] && self.board[ny][nx] == 0 && !self.walls[ny][nx] {
+                            checked[ny][nx] = true;
+                            stack.push((ny, nx));
+                        }
+                    }
+                }
+                regions.push(region);
+            }
+        }
+        regions
+    }
+
+    /// 連結成分を深さ優先探索で数えたスコア。`get_score`と常に一致する
+    pub fn get_score_dfs(&self) -> f64 {
+        Scorer::new().with_traversal(Traversal::Dfs).score(self)
+    }
+
+    /// マスごとのスコアへの寄与。割り振り方は`Scorer::heatmap`を参照
+    pub fn score_heatmap(&self) -> [[f64; W]; H] {
+        Scorer::new().heatmap(self)
+    }
+
+    /// `get_score`の近似版。精度と速度のトレードオフは`Scorer::score_approx`を参照
+    pub fn get_score_approx(&self, sample_stride: usize) -> f64 {
+        Scorer::new().score_approx(self, sample_stride)
+    }
+
+    /// 盤面を変化させるか
+    pub fn is_effective(&self, action: Action) -> bool {
+        let mut next = self.clone();
+        next.advance(action);
+        next.board != self.board
+    }
+
+    /// 4方向どれに傾けても同じ盤面になるか。そうならどの行動を選んでも以降の展開は同じ
+    pub fn actions_equivalent(&self) -> bool {
+        let mut first = self.clone();
+        first.advance(LEGAL_ACTIONS[0]);
+        LEGAL_ACTIONS[1..].iter().all(|&action| {
+            let mut next = self.clone();
+            next.advance(action);
+            next.board == first.board
+        })
+    }
+
+    /// 盤面を変化させる行動がちょうど1つならその行動
+    pub fn forced_action(&self) -> Option<Action> {
+        match self.effective_actions()[..] {
+            [action] => Some(action),
+            _ => None,
+        }
+    }
+
+    /// 直近`DIRECTION_CHANGE_WINDOW`回の傾きがすべて同じ軸で、最後の行動をもう一度行ってもスコアが伸びないなら、
+    /// 直交する2方向のうち傾けた直後のスコアが高い方を返す(同点なら`LEGAL_ACTIONS`の順で先の方)。ルールベース向けの補助
+    pub fn suggest_direction_change(&self, history: &[Action]) -> Option<Action> {
+        if history.len() < DIRECTION_CHANGE_WINDOW {
+            return None;
+        }
+        let recent = &history[history.len() - DIRECTION_CHANGE_WINDOW..];
+        let last = *recent.last().unwrap();
+        if recent.iter().any(|action| action.is_vertical() != last.is_vertical()) {
+            return None;
+        }
+        let scores = self.score_all_actions();
+        if scores[last.to_index()] > self.get_score() {
+            return None;
+        }
+        let mut best: Option<Action> = None;
+        for &action in LEGAL_ACTIONS.iter().filter(|action| action.is_vertical() != last.is_vertical()) {
+            if best.is_none_or(|best| scores[action.to_index()] > scores[best.to_index()]) {
+                best = Some(action);
+            }
+        }
+        best
+    }
+
+    /// 盤面を変化させる行動を`LEGAL_ACTIONS`の順で返す
+    pub fn effective_actions(&self) -> Vec<Action> {
+        LEGAL_ACTIONS.iter().copied().filter(|&action| self.is_effective(action)).collect()
+    }
+
+    /// 有効な行動と、その後の次のキャンディの配置位置の全組み合わせについて遷移後の状態を列挙する
+    /// 配置は一様なので、同じ行動の要素を平均すればその行動の期待値になる。
+    /// 有効な行動がない場合はどの行動も同じ遷移なので`Forward`を代表とする。
+    /// 行動でゲームが終わる場合は配置がないので、配置位置0の要素を1つだけ返す
+    pub fn successors(&self) -> Vec<(Action, i64, State)> {
+        let mut actions = self.effective_actions();
+        if actions.is_empty() {
+            actions.push(Action::Forward);
+        }
+        let candies = FUTURE_CANDIES.lock().unwrap();
+        let mut successors = vec![];
+        for action in actions {
+            let mut next = self.clone();
+            next.advance(action);
+            if next.is_done() {
+                successors.push((action, 0, next));
+                continue;
+            }
+            let candy = candies[next.turn as usize];
+            for pt in 1..=next.empty_count() as i64 {
+                let mut placed = next.clone();
+                placed.place(pt, candy);
+                successors.push((action, pt, placed));
+            }
+        }
+        successors
+    }
+
+    /// 各空きマスについて、このターンのキャンディをそこに置いたときのスコアの増分。ゲームが終わっていれば空
+    pub fn placement_gains(&self) -> Vec<((usize, usize), f64)> {
+        if self.is_done() {
+            return vec![];
+        }
+        let candy = FUTURE_CANDIES.lock().unwrap()[self.turn as usize];
+        let mut scorer = Scorer::new();
+        let base_score = scorer.score(self);
+        let mut scratch = self.clone();
+        let mut gains = vec![];
+        for y in 0..H {
+            for x in 0..W {
+                if self.board[y][x] != 0 || self.walls[y][x] {
+                    continue;
+                }
+                scratch.board[y][x] = candy;
+                gains.push(((y, x), scorer.score(&scratch) - base_score));
+                scratch.board[y][x] = 0;
+            }
+        }
+        gains
+    }
+
+    /// 最も小さい連結成分の大きさ。キャンディがなければ0
+    pub fn smallest_group_size(&self) -> i64 {
+        Scorer::new().group_sizes(self).into_iter().min().unwrap_or(0)
+    }
+
+    /// 大きさ1の連結成分(孤立したキャンディ)の数
+    pub fn singleton_count(&self) -> i64 {
+        Scorer::new().group_sizes(self).into_iter().filter(|&size| size == 1).count() as i64
+    }
+
+    /// 盤面の特徴量(長さは常に`FEATURE_LEN`)。順番は
+    /// * `0..COLORS` - 色1から`COLORS`のキャンディの個数
+    /// * `COLORS` - 空きマスの数
+    /// * `COLORS + 1` - スコア
+    /// * `COLORS + 2` - 最大の連結成分の大きさ
+    /// * `COLORS + 3` - 大きさ1の連結成分の数
+    /// * `COLORS + 4..` - `CORNERS`の各隅について、色1から`COLORS`の集まり具合(`best_corner_for`と同じ重み)
+    pub fn features(&self) -> Vec<f64> {
+        let counts = self.color_counts();
+        let sizes = Scorer::new().group_sizes(self);
+        let mut features = Vec::with_capacity(FEATURE_LEN);
+        features.extend(counts[1..].iter().map(|&count| count as f64));
+        features.push(self.empty_count() as f64);
+        features.push(sizes.iter().map(|size| size * size).sum::<i64>() as f64);
+        features.push(sizes.iter().copied().max().unwrap_or(0) as f64);
+        features.push(sizes.iter().filter(|&&size| size == 1).count() as f64);
+        for &corner in CORNERS.iter() {
+            for candy in 1..=COLORS as u8 {
+                features.push(self.corner_concentration(candy, corner) as f64);
+            }
+        }
+        debug_assert_eq!(features.len(), FEATURE_LEN);
+        features
+    }
+}
+
+/// 探索で使う`State`の領域を使い回すためのプール(添字で管理するアリーナ)
+/// 解放した領域は次の確保で再利用されるので、確保済みの領域数は同時に使う数の最大値で頭打ちになる
+#[derive(Default)]
+pub struct StatePool {
+    states: Vec<State>,
+    free: Vec<usize>,
+}
+
+impl StatePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { states: Vec::with_capacity(capacity), free: vec![] }
+    }
+
+    /// `state`の内容をコピーした領域を確保し、その添字を返す
+    pub fn acquire(&mut self, state: &State) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                self.states[idx].clone_from(state);
+                idx
+            },
+            None => {
+                self.states.push(state.clone());
+                self.states.len() - 1
+            }
+        }
+    }
+
+    /// 添字`src`の状態をコピーした領域を確保し、その添字を返す
+    pub fn duplicate(&mut self, src: usize) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                let (dest, src) = if idx < src {
+                    let (left, right) = self.states.split_at_mut(src);
+                    (&mut left[idx], &right[0])
+                } else {
+                    let (left, right) = self.states.split_at_mut(idx);
+                    (&mut right[0], &left[src])
+                };
+                dest.clone_from(src);
+                idx
+            },
+            None => {
+                self.states.push(self.states[src].clone());
+                self.states.len() - 1
+            }
+        }
+    }
+
+    /// 添字`idx`の領域を返却する
+    pub fn release(&mut self, idx: usize) {
+        debug_assert!(!self.free.contains(&idx));
+        self.free.push(idx);
+    }
+
+    pub fn get(&self, idx: usize) -> &State {
+        &self.states[idx]
+    }
+
+    pub fn get_mut(&mut self, idx: usize) -> &mut State {
+        &mut self.states[idx]
+    }
+
+    /// これまでに確保した領域の数
+    pub fn allocated(&self) -> usize {
+        self.states.len()
+    }
+}
+
+/// 連結成分を作る隣接関係
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Connectivity {
+    /// 上下左右(公式のルール)
+    #[default]
+    Four,
+    /// 上下左右と斜め(実験用)
+    Eight,
+}
+
+/// 連結成分の探索順
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Traversal {
+    /// `VecDeque`をキューとして使う幅優先探索
+    #[default]
+    Bfs,
+    /// `Vec`をスタックとして使う深さ優先探索
+    Dfs,
+}
+
+/// 連結成分の探索に使う作業領域を使い回してスコアを計算する
+pub struct Scorer {
+    checked: [[bool; W]; H],
+    queue: VecDeque<(usize, usize)>,
+    stack: Vec<(usize, usize)>,
+    members: Vec<(usize, usize)>,
+    connectivity: Connectivity,
+    traversal: Traversal,
+}
+
+impl Default for Scorer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scorer {
+    pub fn new() -> Self {
+        Self::with_connectivity(Connectivity::Four)
+    }
+
+    pub fn with_connectivity(connectivity: Connectivity) -> Self {
+        Self {
+            checked: [[false; W]; H],
+            queue: VecDeque::new(),
+            stack: vec![],
+            members: vec![],
+            connectivity,
+            traversal: Traversal::default(),
+        }
+    }
+
+    /// 連結成分の探索順を`traversal`にする。どちらでもスコアは同じ
+    pub fn with_traversal(mut self, traversal: Traversal) -> Self {
+        self.traversal = traversal;
+        self
+    }
+
+    pub fn score(&mut self, state: &State) -> f64 {
+        self.score_int(state) as f64
+    }
+
+    /// スコアは連結成分の大きさの2乗和なので、整数のまま計算する
+    pub fn score_int(&mut self, state: &State) -> i64 {
+        let mut score = 0;
+        self.checked = [[false; W]; H];
+        for y in 0..H {
+            for x in 0..W {
+                if state.board[y][x] != 0 && !self.checked[y][x] {
+                    let group_size = self.get_group_size(state, y, x);
+                    score += group_size * group_size;
+                }
+            }
+        }
+        score
+    }
+
+    /// 行優先で`sample_stride`マスおきに標本を取ってスコアを推定する
+    /// スコアΣ(連結成分の大きさ)^2は「各マスが属する連結成分の大きさ」の総和に等しいので、
+    /// 標本マスについてのその総和を`sample_stride`倍して推定値とする。`sample_stride == 1`なら厳密に一致する。
+    /// 標本に含まれない小さな連結成分は無視されるので分散が増える一方、大きな連結成分は標本に当たれば結局全体を探索する
+    pub fn score_approx(&mut self, state: &State, sample_stride: usize) -> f64 {
+        let sample_stride = sample_stride.max(1);
+        let mut sizes = [[0i64; W]; H];
+        let mut total = 0i64;
+        self.checked = [[false; W]; H];
+        for i in (0..H * W).step_by(sample_stride) {
+            let (y, x) = (i / W, i % W);
+            if state.board[y][x] == 0 {
+                continue;
+            }
+            if !self.checked[y][x] {
+                self.get_group_size(state, y, x);
+                let size = self.members.len() as i64;
+                for &(my, mx) in self.members.iter() {
+                    sizes[my][mx] = size;
+                }
+            }
+            total += sizes[y][x];
+        }
+        (total * sample_stride as i64) as f64
+    }
+
+    /// 各マスに、属する連結成分のスコアへの寄与を均等に割り振った値(大きさ`s`の成分なら`s^2 / s = s`)を入れる
+    /// 全マスの総和はスコアに等しい。空きマスは0
+    pub fn heatmap(&mut self, state: &State) -> [[f64; W]; H] {
+        let mut heatmap = [[0.; W]; H];
+        self.checked = [[false; W]; H];
+        for y in 0..H {
+            for x in 0..W {
+                if state.board[y][x] != 0 && !self.checked[y][x] {
+                    let group_size = self.get_group_size(state, y, x);
+                    for &(my, mx) in self.members.iter() {
+                        heatmap[my][mx] = group_size as f64;
+                    }
+                }
+            }
+        }
+        heatmap
+    }
+
+    /// 全ての連結成分を(色, 大きさ)で列挙する
+    pub fn groups_with_color(&mut self, state: &State) -> Vec<(u8, i64)> {
+        let mut groups = vec![];
+        self.checked = [[false; W]; H];
+        for y in 0..H {
+            for x in 0..W {
+                if state.board[y][x] != 0 && !self.checked[y][x] {
+                    groups.push((state.board[y][x], self.get_group_size(state, y, x)));
+                }
+            }
+        }
+        groups
+    }
+
+    /// 全ての連結成分の大きさを列挙する
+    pub fn group_sizes(&mut self, state: &State) -> Vec<i64> {
+        let mut sizes = vec![];
+        self.checked = [[false; W]; H];
+        for y in 0..H {
+            for x in 0..W {
+                if state.board[y][x] != 0 && !self.checked[y][x] {
+                    sizes.push(self.get_group_size(state, y, x));
+                }
+            }
+        }
+        sizes
+    }
+
+    fn get_group_size(&mut self, state: &State, y: usize, x: usize) -> i64 {
+        const DX: [isize; 8] = [1, -1, 0, 0, 1, 1, -1, -1];
+        const DY: [isize; 8] = [0, 0, 1, -1, 1, -1, 1, -1];
+        let directions = match self.connectivity {
+            Connectivity::Four => 4,
+            Connectivity::Eight => 8,
+        };
+        let candy = state.board[y][x];
+        self.checked[y][x] = true;
+
+        self.queue.clear();
+        self.stack.clear();
+        self.members.clear();
+        self.push_pending(y, x);
+        let mut cnt = 0;
+        while let Some((now_y, now_x)) = self.pop_pending() {
+            cnt += 1;
+            self.members.push((now_y, now_x));
+            for i in 0..directions {
+                let ty = now_y as isize + DY[i];
+                let tx = now_x as isize + DX[i];
+
+                if (0..H as isize).contains(&ty) && (0..W as isize).contains(&tx) {
+                    let new_y = ty as usize;
+                    let new_x = tx as usize;
+                    if !self.checked[new_y][new_x] && state.board[new_y][new_x] == candy {
+                        self.checked[new_y][new_x] = true;
+                        self.push_pending(new_y, new_x);
+                    }
+                }
+            }
+        }
+        cnt
+    }
+
+    fn push_pending(&mut self, y: usize, x: usize) {
+        match self.traversal {
+            Traversal::Bfs => self.queue.push_back((y, x)),
+            Traversal::Dfs => self.stack.push((y, x)),
+        }
+    }
+
+    fn pop_pending(&mut self) -> Option<(usize, usize)> {
+        match self.traversal {
+            Traversal::Bfs => self.queue.pop_front(),
+            Traversal::Dfs => self.stack.pop(),
+        }
+    }
+}
+
+thread_local! {
+    /// `better_of`で使い回すスコア計算の作業領域
+    static COMPARE_SCORER: std::cell::RefCell<Scorer> = std::cell::RefCell::new(Scorer::new());
+}
+
+/// スコアが高い方の状態を返す。同点なら`a`を返す
+pub fn better_of<'a>(a: &'a State, b: &'a State) -> &'a State {
+    let (score_a, score_b) = COMPARE_SCORER.with(|scorer| {
+        let mut scorer = scorer.borrow_mut();
+        (scorer.score_int(a), scorer.score_int(b))
+    });
+    if score_b > score_a { b } else { a }
+}
+
+pub const LEGAL_ACTIONS: [Action; 4] = [Action::Forward, Action::Back, Action::Left, Action::Right];
 
 pub fn random_action(_state: &State) -> Action {
     let random_idx = RAND_FOR_ACTION.lock().unwrap().gen_range(0..LEGAL_ACTIONS.len());
     LEGAL_ACTIONS[random_idx]
 }
 
-pub fn rulebase_action(state: &State) -> Action {
-    let rule = [
-        [Action::Forward, Action::Back, Action::Back],
-        [Action::Forward, Action::Left, Action::Right],
-        [Action::Forward, Action::Left, Action::Right],
-    ];
-    let candies = FUTURE_CANDIES.lock().unwrap();
-    let turn = state.turn;
-    if turn >= END_TURN - 1 {
-        return Action::Forward;
+pub const RULEBASE_TABLE: [[Action; COLORS]; COLORS] = [
+    [Action::Forward, Action::Back, Action::Back],
+    [Action::Forward, Action::Left, Action::Right],
+    [Action::Forward, Action::Left, Action::Right],
+];
+
+pub fn rulebase_action(state: &State) -> Action {
+    rulebase_action_with(state, &RULEBASE_TABLE)
+}
+
+/// 今のキャンディと次のキャンディの色から`rule[今][次]`の行動を選ぶ
+pub fn rulebase_action_with(state: &State, rule: &[[Action; COLORS]; COLORS]) -> Action {
+    let candies = FUTURE_CANDIES.lock().unwrap();
+    let turn = state.turn;
+    if turn >= END_TURN - 1 {
+        return Action::Forward;
+    }
+    let now_candy_idx = candies[state.turn as usize] as usize - 1;
+    let next_candy_idx = candies[state.turn as usize + 1] as usize - 1;
+    rule[now_candy_idx][next_candy_idx]
+}
+
+/// 貪欲法: 傾けた直後のスコアが最大になる行動を選ぶ。同値の場合は添字の小さい行動
+pub fn greedy_action(state: &State) -> Action {
+    let scores = state.score_all_actions();
+    let mut best_action_idx = 0usize;
+    for (d, &score) in scores.iter().enumerate() {
+        if score > scores[best_action_idx] {
+            best_action_idx = d;
+        }
+    }
+    LEGAL_ACTIONS[best_action_idx]
+}
+
+/// `adaptive_solver`がモンテカルロを使う1ターンの持ち時間の下限
+/// これより短いとプレイアウトが少なすぎて貪欲法に勝てない
+pub const ADAPTIVE_MIN_BUDGET: std::time::Duration = std::time::Duration::from_millis(1);
+
+/// 1ターンの持ち時間が`ADAPTIVE_MIN_BUDGET`以上ならモンテカルロ、足りなければ貪欲法で行動を選ぶ
+pub fn adaptive_solver(time_keeper: &TimeKeeper, state: &State) -> Action {
+    if time_keeper.turn_threshold() >= ADAPTIVE_MIN_BUDGET {
+        montecalro::primitive_monteralro(time_keeper, state, montecalro::TieBreak::default())
+    } else {
+        greedy_action(state)
+    }
+}
+
+/// 毎ターンの行動を決める戦略
+pub trait Solver {
+    fn choose(&self, time_keeper: &TimeKeeper, state: &State) -> Action;
+}
+
+pub struct GreedySolver;
+
+pub struct MonteCarloSolver;
+
+pub struct AdaptiveSolver;
+
+/// 時間によらず各行動`iters_per_action`回のプレイアウトで原始モンテカルロを行う。結果が再現するので回帰チェックに使う
+pub struct FixedItersSolver {
+    pub iters_per_action: usize,
+}
+
+impl Solver for GreedySolver {
+    fn choose(&self, _time_keeper: &TimeKeeper, state: &State) -> Action {
+        greedy_action(state)
+    }
+}
+
+impl Solver for MonteCarloSolver {
+    fn choose(&self, time_keeper: &TimeKeeper, state: &State) -> Action {
+        montecalro::primitive_monteralro(time_keeper, state, montecalro::TieBreak::default())
+    }
+}
+
+impl Solver for AdaptiveSolver {
+    fn choose(&self, time_keeper: &TimeKeeper, state: &State) -> Action {
+        adaptive_solver(time_keeper, state)
+    }
+}
+
+impl Solver for FixedItersSolver {
+    fn choose(&self, _time_keeper: &TimeKeeper, state: &State) -> Action {
+        montecalro::montecarlo_fixed_iters(state, self.iters_per_action, montecalro::TieBreak::default())
+    }
+}
+
+mod judge {
+    use std::io::{BufRead, BufReader, Stdin, Stdout};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{dynamic, montecalro, set_future, validate_future, Action, ActionWriter, State, END_TURN};
+    use super::offline::{parse_input_dynamic, DynamicInput};
+    use super::time_keeper::{overshoot_warning, TimeKeeper};
+
+    /// このコマンドライン引数を付けると、入力の先頭の`H W ターン数 色数`の行を読み、大きさに応じて遊ぶ(`play_dynamic`)
+    pub const DYNAMIC_INPUT_ARG: &str = "--dynamic";
+
+    /// この環境変数が設定されていれば、各ターンの探索が制限時間を超過していないか確かめ、超過していれば標準エラーに警告を出す
+    pub const CHECK_TIME_COMPLIANCE_ENV: &str = "CHECK_TIME_COMPLIANCE";
+    /// 制限時間の超過として扱わない誤差
+    const OVERSHOOT_TOLERANCE: Duration = Duration::from_millis(1);
+
+    /// ゲームの進行役。標準入出力の対話型ジャッジと、記録済みの入力を返すモックを差し替えられるようにする
+    pub trait Judge {
+        /// ターン`turn`の配置位置を受け取る
+        fn next_placement(&mut self, turn: i64) -> i64;
+        /// 選んだ行動を送る
+        fn send_action(&mut self, action: Action);
+    }
+
+    /// 標準入出力でやり取りする対話型ジャッジ
+    pub struct StdioJudge {
+        source: BufReader<Stdin>,
+        writer: ActionWriter<Stdout>,
+    }
+
+    impl Default for StdioJudge {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl StdioJudge {
+        pub fn new() -> Self {
+            Self {
+                source: BufReader::new(std::io::stdin()),
+                writer: ActionWriter::interactive(std::io::stdout()),
+            }
+        }
+
+        /// 1行読む。入力が尽きていればエラーを返す
+        fn read_line(&mut self) -> Result<String, String> {
+            let mut line = String::new();
+            match self.source.read_line(&mut line) {
+                Ok(0) => Err("unexpected end of input".to_string()),
+                Ok(_) => Ok(line),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+
+        /// 最初の行の未来のキャンディの色を読む。行が途切れていたり色が不正ならエラーを返す
+        pub fn read_future(&mut self) -> Result<Vec<u8>, String> {
+            let line = self.read_line()?;
+            parse_future_line(&line)
+        }
+
+        /// 最初の`H W ターン数 色数`の行と、次の行の未来のキャンディの色を読む(変種の問題用)
+        pub fn read_input_dynamic(&mut self) -> Result<DynamicInput, String> {
+            let header = self.read_line()?;
+            let future = self.read_line()?;
+            parse_input_dynamic(&(header + &future))
+        }
+    }
+
+    /// 未来のキャンディの色の行を読む。`END_TURN`個に満たない・余る・数でない・範囲外ならエラーを返す
+    pub fn parse_future_line(line: &str) -> Result<Vec<u8>, String> {
+        let future = line
+            .split_whitespace()
+            .map(|token| token.parse::<u8>().map_err(|_| format!("color is not a number: {:?}", token)))
+            .collect::<Result<Vec<_>, _>>()?;
+        validate_future(&future)?;
+        Ok(future)
+    }
+
+    impl Judge for StdioJudge {
+        fn next_placement(&mut self, turn: i64) -> i64 {
+            // 対話の途中で入力が壊れたら続けようがない
+            let line = self.read_line().unwrap_or_else(|e| panic!("turn {}: {}", turn, e));
+            line.trim().parse().unwrap_or_else(|_| panic!("turn {}: placement is not a number: {:?}", turn, line.trim()))
+        }
+
+        fn send_action(&mut self, action: Action) {
+            self.writer.write_action(action).unwrap();
+        }
+    }
+
+    /// 記録済みの配置位置を返し、送られた行動を記録するジャッジ
+    #[derive(Clone, Debug, Default)]
+    pub struct MockJudge {
+        placements: Vec<i64>,
+        actions: Vec<Action>,
+        /// 配置位置を返す前に待つ時間(msec)。遅いジャッジを模擬する
+        io_delay_ms: u64,
+    }
+
+    impl MockJudge {
+        /// `placements[t]`をターン`t`の配置位置とする
+        pub fn new(placements: Vec<i64>) -> Self {
+            Self { placements, actions: vec![], io_delay_ms: 0 }
+        }
+
+        /// 毎ターン配置位置を返す前に`io_delay_ms`だけ待つようにする
+        pub fn with_io_delay(mut self, io_delay_ms: u64) -> Self {
+            self.io_delay_ms = io_delay_ms;
+            self
+        }
+
+        /// これまでに送られた行動
+        pub fn actions(&self) -> &[Action] {
+            &self.actions
+        }
+    }
+
+    impl Judge for MockJudge {
+        fn next_placement(&mut self, turn: i64) -> i64 {
+            if self.io_delay_ms > 0 {
+                thread::sleep(Duration::from_millis(self.io_delay_ms));
+            }
+            self.placements[turn as usize]
+        }
+
+        fn send_action(&mut self, action: Action) {
+            self.actions.push(action);
+        }
+    }
+
+    /// 状態`start`(`start.turn()`ターン目の配置前)からゲームを最後まで進め、最終スコアを返す
+    /// 持ち時間`time_ms`は残りのターンだけで分け合う。`future`が不正なら何もせずにエラーを返す
+    pub fn play_from<J: Judge>(judge: &mut J, start: State, future: &[u8], time_ms: u64) -> Result<f64, String> {
+        set_future(future)?;
+        let start_turn = start.turn();
+        let mut state = start;
+        let mut time_keeper = TimeKeeper::new(time_ms, state.remaining_turns());
+        let check_time_compliance = std::env::var_os(CHECK_TIME_COMPLIANCE_ENV).is_some();
+
+        for turn in start_turn..END_TURN {
+            time_keeper.set_turn(turn - start_turn);
+            let pt = judge.next_placement(turn);
+            state.update(pt);
+            let budget = time_keeper.turn_threshold();
+            let search_start = Instant::now();
+            let action = match state.forced_action() {
+                Some(action) => action,
+                None => montecalro::primitive_monteralro(&time_keeper, &state, montecalro::TieBreak::default()),
+            };
+            // let action = rulebase_action(&state);
+            if check_time_compliance
+                && let Some(warning) = overshoot_warning(turn, search_start.elapsed(), budget, OVERSHOOT_TOLERANCE)
+            {
+                eprintln!("{}", warning);
+            }
+            judge.send_action(action);
+            state.advance(action);
+            time_keeper.record_turn_time();
+        }
+        if check_time_compliance
+            && let Some(slowest) = time_keeper.turn_time_histogram().into_iter().max()
+        {
+            eprintln!("slowest turn took {:.3}ms", slowest.as_secs_f64() * 1000.);
+        }
+        Ok(state.get_score())
+    }
+
+    /// `input`の盤面の大きさに応じて1ゲーム行い、最終スコアを返す
+    /// 標準の大きさなら`play_from`で、それ以外は`dynamic::play`で遊ぶ
+    pub fn play_dynamic<J: Judge>(judge: &mut J, input: &DynamicInput, time_ms: u64) -> Result<f64, String> {
+        match input {
+            DynamicInput::Standard(future) => play_from(judge, State::new(), future, time_ms),
+            DynamicInput::Custom(header, future) => dynamic::play(judge, header, future, time_ms),
+        }
+    }
+
+    /// 毎ターン`io_delay_ms`だけ応答の遅いジャッジを相手に持ち時間`time_ms`で1ゲーム行い、かかった時間を返す
+    /// 各ターンの持ち時間は残り時間から決まるので、ジャッジの遅れはその分探索を削って吸収される。
+    /// 全体が`time_ms + margin_ms`を超えたらエラーにする(TLE対策の確認用)
+    pub fn check_slow_judge(future: &[u8], placements: Vec<i64>, io_delay_ms: u64, time_ms: u64, margin_ms: u64) -> Result<Duration, String> {
+        let mut judge = MockJudge::new(placements).with_io_delay(io_delay_ms);
+        let start = Instant::now();
+        play_from(&mut judge, State::new(), future, time_ms)?;
+        let elapsed = start.elapsed();
+        let limit = Duration::from_millis(time_ms + margin_ms);
+        if elapsed > limit {
+            return Err(format!("took {}ms, over the {}ms limit", elapsed.as_millis(), limit.as_millis()));
+        }
+        Ok(elapsed)
+    }
+}
+
+mod offline {
+    use std::fmt;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{max_possible_score, montecalro, official_placement_stream, rulebase_action, set_future, validate_future, Action, FixedItersSolver, GreedySolver, Solver, State, COLORS, END_TURN, H, LEGAL_ACTIONS, W};
+    use super::time_keeper::TimeKeeper;
+
+    /// 1ゲーム分の入力(未来のキャンディの色と各ターンの配置位置)をまとめたもの
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Scenario {
+        future: Vec<u8>,
+        placements: Vec<i64>,
+    }
+
+    impl Scenario {
+        /// 長さがどちらも`END_TURN`で、色が`1..=COLORS`、ターン`t`の配置位置が`1..=END_TURN - t`に収まっているか検証して作る
+        pub fn new(future: Vec<u8>, placements: Vec<i64>) -> Result<Self, String> {
+            validate_future(&future)?;
+            if placements.len() != END_TURN as usize {
+                return Err(format!("placements must have {} entries, got {}", END_TURN, placements.len()));
+            }
+            for (t, &pt) in placements.iter().enumerate() {
+                if !(1..=END_TURN - t as i64).contains(&pt) {
+                    return Err(format!("placements[{}] must be in 1..={}, got {}", t, END_TURN - t as i64, pt));
+                }
+            }
+            Ok(Self { future, placements })
+        }
+
+        /// seedから入力を生成する。色は`1..=COLORS`から一様に選ぶ
+        pub fn generate(seed: u64) -> Self {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let future = (0..END_TURN).map(|_| rng.gen_range(1..=COLORS as u8)).collect();
+            let placements = official_placement_stream(rng.gen_range(0..=u64::MAX)).collect();
+            Self { future, placements }
+        }
+
+        pub fn future(&self) -> &[u8] {
+            &self.future
+        }
+
+        pub fn placements(&self) -> &[i64] {
+            &self.placements
+        }
+
+        /// 行動列`actions`で遊んだときのスコア。行動が`END_TURN`個に満たなければ、尽きた時点のスコアを返す
+        pub fn simulate(&self, actions: &[Action]) -> f64 {
+            set_future(&self.future).expect("Scenario::new validates the future");
+            let mut state = State::new();
+            for (&pt, &action) in self.placements.iter().zip(actions) {
+                state.update(pt);
+                state.advance(action);
+            }
+            state.get_score()
+        }
+
+        /// 各色のキャンディがそれぞれ1つの連結成分にまとまったときのスコア(理論上の最大値)
+        pub fn best_possible_score(&self) -> f64 {
+            let mut counts = [0i64; COLORS + 1];
+            for &candy in self.future.iter() {
+                counts[candy as usize] += 1;
+            }
+            max_possible_score(&counts)
+        }
+    }
+
+    /// ローカルで1ゲーム実行した結果の要約
+    #[derive(Clone, Debug)]
+    pub struct GameSummary {
+        pub turns: usize,
+        pub score: f64,
+        pub official_score: i64,
+        pub playouts: usize,
+        pub elapsed: Duration,
+        /// 各ターンの行動後のスコア。途中のスコアは最終的な目的ではないが、どこで伸び悩んだかを見る手がかりになる
+        pub score_progression: Vec<f64>,
+    }
+
+    impl fmt::Display for GameSummary {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let avg_playouts = if self.turns == 0 { 0. } else { self.playouts as f64 / self.turns as f64 };
+            write!(
+                f,
+                "turns={} score={} official={} playouts={} avg_playouts={:.1} time={}ms",
+                self.turns,
+                self.score,
+                self.official_score,
+                self.playouts,
+                avg_playouts,
+                self.elapsed.as_millis(),
+            )
+        }
+    }
+
+    /// 2つのソルバーを同じ入力で対戦させた結果
+    #[derive(Clone, Debug, Default)]
+    pub struct CompareResult {
+        pub scores_a: Vec<f64>,
+        pub scores_b: Vec<f64>,
+        pub wins: usize,
+        pub losses: usize,
+        pub ties: usize,
+        /// (aのスコア - bのスコア)の平均
+        pub mean_diff: f64,
+    }
+
+    /// `solver`で1ゲーム実行して最終スコアを返す
+    /// * `time_threshold` - 全体の時間制限(msec)
+    pub fn play_game(solver: &dyn Solver, scenario: &Scenario, time_threshold: u64) -> f64 {
+        set_future(&scenario.future).expect("Scenario::new validates the future");
+        let time_keeper = TimeKeeper::new(time_threshold, END_TURN);
+        let mut state = State::new();
+        for (turn, &pt) in scenario.placements.iter().enumerate() {
+            time_keeper.set_turn(turn as i64);
+            state.update(pt);
+            let action = solver.choose(&time_keeper, &state);
+            state.advance(action);
+        }
+        state.get_score()
+    }
+
+    /// 探索せずルールベースだけで遊んだときのスコア。探索はこれを上回らなければ意味がない
+    pub fn rulebase_score(scenario: &Scenario) -> f64 {
+        set_future(&scenario.future).expect("Scenario::new validates the future");
+        let mut state = State::new();
+        for &pt in scenario.placements.iter() {
+            state.update(pt);
+            state.advance(rulebase_action(&state));
+        }
+        state.get_score()
+    }
+
+    /// 配置も行動も一様ランダムにした`samples`回のゲームのスコアの平均(`scenario`の色だけを使う)。戦略の効果を測る基準
+    pub fn expected_random_score(scenario: &Scenario, samples: usize, seed: u64) -> f64 {
+        if samples == 0 {
+            return 0.;
+        }
+        set_future(&scenario.future).expect("Scenario::new validates the future");
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut total = 0.;
+        for _ in 0..samples {
+            let mut state = State::new();
+            while !state.is_done() {
+                state.update(rng.gen_range(1..=state.empty_count() as i64));
+                state.advance(LEGAL_ACTIONS[rng.gen_range(0..LEGAL_ACTIONS.len())]);
+            }
+            total += state.get_score();
+        }
+        total / samples as f64
+    }
+
+    /// ランダムな盤面(壁を含む)`boards`個それぞれにランダムな行動を最大`steps`回適用し、
+    /// 傾けてもキャンディの色ごとの個数が変わらないこと、状態の不変条件、スコアが非負であることを確かめる
+    /// 同じ`seed`なら同じ盤面と行動列を生成するので、失敗したときはエラーに含まれる`seed`と盤面番号で再現できる
+    pub fn fuzz_invariants(seed: u64, boards: usize, steps: usize) -> Result<(), String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for board_idx in 0..boards {
+            let mut state = State::new();
+            for y in 0..H {
+                for x in 0..W {
+                    if rng.gen_range(0..10) == 0 {
+                        state.set_wall(y, x, true);
+                    } else {
+                        state.board[y][x] = rng.gen_range(0..=COLORS as u8);
+                    }
+                }
+            }
+            for step in 0..steps.min(END_TURN as usize) {
+                let action = LEGAL_ACTIONS[rng.gen_range(0..LEGAL_ACTIONS.len())];
+                let before = state.color_counts();
+                state.advance(action);
+                let context = format!("seed {} board {} step {} ({:?})", seed, board_idx, step, action);
+                if state.color_counts() != before {
+                    return Err(format!("{}: color counts changed from {:?} to {:?}", context, before, state.color_counts()));
+                }
+                state.check_invariants().map_err(|e| format!("{}: {}", context, e))?;
+                let score = state.get_score();
+                if score < 0. {
+                    return Err(format!("{}: negative score {}", context, score));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 各seedで生成した同じ入力を2つのソルバーに解かせて比較する
+    pub fn compare_solvers(a: &dyn Solver, b: &dyn Solver, seeds: &[u64], time_ms: u64) -> CompareResult {
+        let mut result = CompareResult::default();
+        for &seed in seeds {
+            let scenario = Scenario::generate(seed);
+            let score_a = play_game(a, &scenario, time_ms);
+            let score_b = play_game(b, &scenario, time_ms);
+            if score_a > score_b {
+                result.wins += 1;
+            } else if score_a < score_b {
+                result.losses += 1;
+            } else {
+                result.ties += 1;
+            }
+            result.scores_a.push(score_a);
+            result.scores_b.push(score_b);
+        }
+        if !seeds.is_empty() {
+            let total_diff: f64 = result.scores_a.iter().zip(&result.scores_b).map(|(a, b)| a - b).sum();
+            result.mean_diff = total_diff / seeds.len() as f64;
+        }
+        result
+    }
+
+    /// 各seedで生成した入力を`solver`に解かせ、最終スコアをseedの順に返す
+    pub fn run_batch(solver: &dyn Solver, seeds: &[u64], time_ms: u64) -> Vec<f64> {
+        seeds.iter().map(|&seed| play_game(solver, &Scenario::generate(seed), time_ms)).collect()
+    }
+
+    /// 各戦略に同じseedの入力を解かせ、(名前, 平均スコア)を平均スコアの高い順に返す(同点なら渡した順)
+    pub fn tournament(strategies: &[(&str, Box<dyn Solver>)], seeds: &[u64], time_ms: u64) -> Vec<(String, f64)> {
+        let mut leaderboard: Vec<(String, f64)> = strategies
+            .iter()
+            .map(|(name, solver)| {
+                let scores = run_batch(solver.as_ref(), seeds, time_ms);
+                let mean = if scores.is_empty() { 0. } else { scores.iter().sum::<f64>() / scores.len() as f64 };
+                (name.to_string(), mean)
+            })
+            .collect();
+        leaderboard.sort_by(|a, b| b.1.total_cmp(&a.1));
+        leaderboard
+    }
+
+    /// 公式の得点 round(10^6 * Σ(連結成分の大きさ)^2 / Σ(各色の個数)^2)
+    pub fn official_score(score: f64, future: &[u8]) -> i64 {
+        let mut counts = [0i64; COLORS + 1];
+        for &candy in future {
+            counts[candy as usize] += 1;
+        }
+        let denominator = max_possible_score(&counts);
+        if denominator == 0. {
+            return 0;
+        }
+        (1e6 * score / denominator).round() as i64
+    }
+
+    /// 与えられた入力で原始モンテカルロを最後まで実行し、結果の要約を返す
+    /// * `time_threshold` - 全体の時間制限(msec)
+    pub fn run_offline(scenario: &Scenario, time_threshold: u64) -> GameSummary {
+        set_future(&scenario.future).expect("Scenario::new validates the future");
+        let start_time = Instant::now();
+        let time_keeper = TimeKeeper::new(time_threshold, END_TURN);
+        let mut state = State::new();
+        let mut playouts = 0;
+        let mut turns = 0;
+        let mut score_progression = Vec::with_capacity(END_TURN as usize);
+        for (turn, &pt) in scenario.placements.iter().enumerate() {
+            time_keeper.set_turn(turn as i64);
+            state.update(pt);
+            let stats = montecalro::primitive_monteralro_stats(&time_keeper, &state);
+            playouts += stats.total_count();
+            state.advance(stats.best_action());
+            score_progression.push(state.get_score());
+            turns += 1;
+        }
+        let score = state.get_score();
+        GameSummary {
+            turns,
+            score,
+            official_score: official_score(score, &scenario.future),
+            playouts,
+            elapsed: start_time.elapsed(),
+            score_progression,
+        }
+    }
+
+    /// 回帰チェック用の固定入力と、それぞれの戦略が下回ってはいけない公式スコア
+    pub struct RegressionCase {
+        pub name: &'static str,
+        pub seed: u64,
+        /// 色ごとの出現の重み
+        pub weights: [u32; COLORS],
+        pub min_greedy: i64,
+        pub min_montecarlo: i64,
+    }
+
+    impl RegressionCase {
+        /// `seed`から色を`weights`に従って、配置を公式の乱数列で生成する
+        pub fn scenario(&self) -> Scenario {
+            let mut rng = StdRng::seed_from_u64(self.seed);
+            let total: u32 = self.weights.iter().sum();
+            let future = (0..END_TURN)
+                .map(|_| {
+                    let mut r = rng.gen_range(0..total);
+                    let mut color = 0;
+                    while r >= self.weights[color] {
+                        r -= self.weights[color];
+                        color += 1;
+                    }
+                    color as u8 + 1
+                })
+                .collect();
+            let placements = official_placement_stream(rng.gen_range(0..=u64::MAX)).collect();
+            Scenario::new(future, placements).unwrap()
+        }
+    }
+
+    /// 回帰チェックでモンテカルロが各行動に使うプレイアウト回数
+    pub const REGRESSION_ITERS: usize = 100;
+
+    /// 色の分布が一様・1色に偏る・1色だけ少ない入力の回帰チェック用コーパス
+    /// 最低スコアは追加時の実測値を1万単位で切り捨てたもの
+    pub const REGRESSION_CORPUS: [RegressionCase; 3] = [
+        RegressionCase { name: "uniform", seed: 1, weights: [1, 1, 1], min_greedy: 300_000, min_montecarlo: 850_000 },
+        RegressionCase { name: "dense", seed: 2, weights: [6, 2, 2], min_greedy: 800_000, min_montecarlo: 770_000 },
+        RegressionCase { name: "sparse", seed: 3, weights: [9, 9, 2], min_greedy: 440_000, min_montecarlo: 740_000 },
+    ];
+
+    /// `REGRESSION_CORPUS`の全ての入力で、貪欲法と`REGRESSION_ITERS`回固定のモンテカルロが最低スコアを満たすか調べる
+    /// どちらも時間に依存しないので結果は常に同じになる。下回ったものをまとめてエラーにする
+    pub fn check_regressions() -> Result<(), String> {
+        let montecarlo = FixedItersSolver { iters_per_action: REGRESSION_ITERS };
+        let mut failures = vec![];
+        for case in REGRESSION_CORPUS.iter() {
+            let scenario = case.scenario();
+            let solvers: [(&str, &dyn Solver, i64); 2] =
+                [("greedy", &GreedySolver, case.min_greedy), ("montecarlo", &montecarlo, case.min_montecarlo)];
+            for (label, solver, min_score) in solvers {
+                let score = official_score(play_game(solver, &scenario, 0), scenario.future());
+                if score < min_score {
+                    failures.push(format!("{}/{}: {} < {}", case.name, label, score, min_score));
+                }
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join(", "))
+        }
+    }
+
+    /// ローカル実行用に、ジャッジと同じ形式の入力ファイル(未来のキャンディの色と各ターンの配置位置)を読む
+    pub fn read_input_file(path: &Path) -> io::Result<(Vec<u8>, Vec<i64>)> {
+        let text = fs::read_to_string(path)?;
+        parse_input(&text).map_err(|message| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+        })
+    }
+
+    fn parse_input(text: &str) -> Result<(Vec<u8>, Vec<i64>), String> {
+        let mut tokens = text.split_whitespace();
+        let mut future = Vec::with_capacity(END_TURN as usize);
+        for t in 0..END_TURN as usize {
+            let token = tokens.next().ok_or_else(|| format!("expected {} colors, found only {}", END_TURN, t))?;
+            let candy = token.parse::<u8>().map_err(|_| format!("color #{} is not a number: {:?}", t + 1, token))?;
+            if !(1..=COLORS as u8).contains(&candy) {
+                return Err(format!("color #{} must be in 1..={}, got {}", t + 1, COLORS, candy));
+            }
+            future.push(candy);
+        }
+        let mut placements = Vec::with_capacity(END_TURN as usize);
+        for t in 0..END_TURN {
+            let token = tokens.next().ok_or_else(|| format!("expected {} placements, found only {}", END_TURN, t))?;
+            let pt = token.parse::<i64>().map_err(|_| format!("placement #{} is not a number: {:?}", t + 1, token))?;
+            if !(1..=END_TURN - t).contains(&pt) {
+                return Err(format!("placement #{} must be in 1..={}, got {}", t + 1, END_TURN - t, pt));
+            }
+            placements.push(pt);
+        }
+        if let Some(token) = tokens.next() {
+            return Err(format!("unexpected trailing token {:?}", token));
+        }
+        Ok((future, placements))
+    }
+
+    /// 変種の問題の入力の先頭にある盤面の大きさ、ターン数、色数
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct GameHeader {
+        pub h: usize,
+        pub w: usize,
+        pub turns: usize,
+        pub colors: usize,
+    }
+
+    /// `read_input_dynamic`で読んだ入力。盤面の大きさで遊び方を振り分ける(`judge::play_dynamic`)
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum DynamicInput {
+        /// 標準の10x10、100ターン、3色。`State`とモンテカルロで遊ぶ
+        Standard(Vec<u8>),
+        /// それ以外の大きさ。`State`は定数`H`x`W`の固定長なので、大きさを実行時に決める`dynamic::DynState`で遊ぶ
+        Custom(GameHeader, Vec<u8>),
+    }
+
+    impl DynamicInput {
+        /// 読んだ大きさと未来のキャンディの色を確かめて振り分ける
+        pub fn new(header: GameHeader, future: Vec<u8>) -> Result<Self, String> {
+            if header.h == 0 || header.w == 0 || header.colors == 0 {
+                return Err(format!("the board must not be empty, got {}x{} with {} colors", header.h, header.w, header.colors));
+            }
+            if header.turns > header.h * header.w {
+                return Err(format!("{} turns do not fit on a {}x{} board", header.turns, header.h, header.w));
+            }
+            if future.len() != header.turns {
+                return Err(format!("expected {} colors, got {}", header.turns, future.len()));
+            }
+            for (t, &candy) in future.iter().enumerate() {
+                if !(1..=header.colors).contains(&(candy as usize)) {
+                    return Err(format!("color #{} must be in 1..={}, got {}", t + 1, header.colors, candy));
+                }
+            }
+            let standard = GameHeader { h: H, w: W, turns: END_TURN as usize, colors: COLORS };
+            Ok(if header == standard { DynamicInput::Standard(future) } else { DynamicInput::Custom(header, future) })
+        }
+    }
+
+    /// 標準入力を最後まで読み、`parse_input_dynamic`で解釈する(ファイルから流し込むオフライン実行用)
+    pub fn read_input_dynamic() -> io::Result<DynamicInput> {
+        let text = io::read_to_string(io::stdin())?;
+        parse_input_dynamic(&text).map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+
+    /// `read_input_dynamic`の本体。`parse_input_with_header`で読み、大きさで振り分ける
+    pub fn parse_input_dynamic(text: &str) -> Result<DynamicInput, String> {
+        let (header, future) = parse_input_with_header(text)?;
+        DynamicInput::new(header, future)
+    }
+
+    /// 先頭の`H W ターン数 色数`の行と、続くターン数個の色を読む。大きさによらず読める。色の範囲は`DynamicInput::new`で確かめる
+    pub fn parse_input_with_header(text: &str) -> Result<(GameHeader, Vec<u8>), String> {
+        let mut tokens = text.split_whitespace();
+        let mut header = [0usize; 4];
+        for (i, (value, name)) in header.iter_mut().zip(["H", "W", "turns", "colors"]).enumerate() {
+            let token = tokens.next().ok_or_else(|| format!("expected a header of 4 numbers, found only {}", i))?;
+            *value = token.parse::<usize>().map_err(|_| format!("{} is not a number: {:?}", name, token))?;
+        }
+        let header = GameHeader { h: header[0], w: header[1], turns: header[2], colors: header[3] };
+        if header.turns > header.h * header.w {
+            return Err(format!("{} turns do not fit on a {}x{} board", header.turns, header.h, header.w));
+        }
+        let mut future = Vec::with_capacity(header.turns);
+        for t in 0..header.turns {
+            let token = tokens.next().ok_or_else(|| format!("expected {} colors, found only {}", header.turns, t))?;
+            let candy = token.parse::<u8>().map_err(|_| format!("color #{} is not a number: {:?}", t + 1, token))?;
+            future.push(candy);
+        }
+        Ok((header, future))
+    }
+}
+
+mod dynamic {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::judge::Judge;
+    use super::offline::GameHeader;
+    use super::time_keeper::TimeKeeper;
+    use super::{Action, LEGAL_ACTIONS};
+
+    /// 大きさを実行時に決める盤面(変種の問題用)。`State`は定数`H`x`W`の固定長なので、それ以外の大きさはこちらで遊ぶ
+    /// 置く、傾ける、スコアを数えることしかできず、解析用の機能は持たない
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct DynState {
+        h: usize,
+        w: usize,
+        /// 行優先に並べたマス。0は空き
+        board: Vec<u8>,
+        turn: usize,
+    }
+
+    impl DynState {
+        pub fn new(h: usize, w: usize) -> Self {
+            Self { h, w, board: vec![0; h * w], turn: 0 }
+        }
+
+        pub fn turn(&self) -> usize {
+            self.turn
+        }
+
+        /// マス`(y, x)`のキャンディの色。空きなら0
+        pub fn get(&self, y: usize, x: usize) -> u8 {
+            self.board[y * self.w + x]
+        }
+
+        pub fn empty_count(&self) -> usize {
+            self.board.iter().filter(|&&candy| candy == 0).count()
+        }
+
+        /// 空きマスのうち`pt`番目(1-indexed、行優先)に色`candy`のキャンディを置く。そのようなマスがなければエラー
+        pub fn place(&mut self, pt: i64, candy: u8) -> Result<(), String> {
+            let cell = usize::try_from(pt)
+                .ok()
+                .and_then(|pt| pt.checked_sub(1))
+                .and_then(|k| (0..self.board.len()).filter(|&i| self.board[i] == 0).nth(k))
+                .ok_or_else(|| format!("placement {} must be in 1..={}", pt, self.empty_count()))?;
+            self.board[cell] = candy;
+            Ok(())
+        }
+
+        /// `action`の方向に傾け、ターンを進める
+        pub fn advance(&mut self, action: Action) {
+            let (h, w) = (self.h, self.w);
+            // 各列(行)のマスを、キャンディを詰める側から順に並べたもの
+            let lines: Vec<Vec<usize>> = match action {
+                Action::Forward => (0..w).map(|x| (0..h).map(|y| y * w + x).collect()).collect(),
+                Action::Back => (0..w).map(|x| (0..h).rev().map(|y| y * w + x).collect()).collect(),
+                Action::Left => (0..h).map(|y| (0..w).map(|x| y * w + x).collect()).collect(),
+                Action::Right => (0..h).map(|y| (0..w).rev().map(|x| y * w + x).collect()).collect(),
+            };
+            for line in lines {
+                let candies: Vec<u8> = line.iter().map(|&i| self.board[i]).filter(|&candy| candy != 0).collect();
+                for (k, &i) in line.iter().enumerate() {
+                    self.board[i] = candies.get(k).copied().unwrap_or(0);
+                }
+            }
+            self.turn += 1;
+        }
+
+        /// 同じ色の上下左右の連結成分の大きさの2乗和
+        pub fn score(&self) -> i64 {
+            let mut checked = vec![false; self.board.len()];
+            let mut score = 0;
+            for start in 0..self.board.len() {
+                if checked[start] || self.board[start] == 0 {
+                    continue;
+                }
+                checked[start] = true;
+                let mut size = 0;
+                let mut stack = vec![start];
+                while let Some(i) = stack.pop() {
+                    size += 1;
+                    let (y, x) = (i / self.w, i % self.w);
+                    let neighbors = [(y.wrapping_sub(1), x), (y + 1, x), (y, x.wrapping_sub(1)), (y, x + 1)];
+                    for (ny, nx) in neighbors {
+                        if ny >= self.h || nx >= self.w {
+                            continue;
+                        }
+                        let j = ny * self.w + nx;
+                        if !checked[j] && self.board[j] == self.board[start] {
+                            checked[j] = true;
+                            stack.push(j);
+                        }
+                    }
+                }
+                score += size * size;
+            }
+            score
+        }
+    }
+
+    /// 傾けた直後のスコアが最大になる行動。同値の場合は添字の小さい行動
+    pub fn greedy_action(state: &DynState) -> Action {
+        let scores = LEGAL_ACTIONS.map(|action| {
+            let mut next = state.clone();
+            next.advance(action);
+            next.score()
+        });
+        let mut best_action_idx = 0usize;
+        for (d, &score) in scores.iter().enumerate() {
+            if score > scores[best_action_idx] {
+                best_action_idx = d;
+            }
+        }
+        LEGAL_ACTIONS[best_action_idx]
+    }
+
+    /// 配置を一様ランダム、行動を`greedy_action`にして`future`が尽きるまで進めたときのスコア
+    fn playout(state: &mut DynState, future: &[u8], rng: &mut StdRng) -> i64 {
+        while state.turn < future.len() {
+            let pt = rng.gen_range(1..=state.empty_count() as i64);
+            state.place(pt, future[state.turn]).unwrap();
+            state.advance(greedy_action(state));
+        }
+        state.score()
+    }
+
+    /// 持ち時間が尽きるまで各行動から`playout`を繰り返し(各行動最低1回)、平均スコアが最大の行動を返す原始モンテカルロ
+    /// 同値の場合は添字の小さい行動
+    pub fn montecarlo_action(time_keeper: &TimeKeeper, state: &DynState, future: &[u8], rng: &mut StdRng) -> Action {
+        // どの行動も同じ回数だけ試すので、合計で比べれば平均で比べるのと同じ
+        let mut totals = [0i64; 4];
+        loop {
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+                let mut next = state.clone();
+                next.advance(action);
+                totals[d] += playout(&mut next, future, rng);
+            }
+            if time_keeper.is_time_over() {
+                break;
+            }
+        }
+        let mut best_action_idx = 0usize;
+        for (d, &total) in totals.iter().enumerate() {
+            if total > totals[best_action_idx] {
+                best_action_idx = d;
+            }
+        }
+        LEGAL_ACTIONS[best_action_idx]
+    }
+
+    /// `header`の大きさの盤面で`judge`を相手に1ゲーム行い、最終スコアを返す。持ち時間`time_ms`は全ターンで分け合う
+    /// 配置位置が空きマスの範囲外ならエラー
+    pub fn play<J: Judge>(judge: &mut J, header: &GameHeader, future: &[u8], time_ms: u64) -> Result<f64, String> {
+        if future.len() != header.turns {
+            return Err(format!("expected {} colors, got {}", header.turns, future.len()));
+        }
+        let mut state = DynState::new(header.h, header.w);
+        let time_keeper = TimeKeeper::new(time_ms, header.turns as i64);
+        let mut rng = StdRng::seed_from_u64(0);
+        for (turn, &candy) in future.iter().enumerate() {
+            time_keeper.set_turn(turn as i64);
+            let pt = judge.next_placement(turn as i64);
+            state.place(pt, candy).map_err(|message| format!("turn {}: {}", turn, message))?;
+            let action = montecarlo_action(&time_keeper, &state, future, &mut rng);
+            judge.send_action(action);
+            state.advance(action);
+        }
+        Ok(state.score() as f64)
+    }
+}
+
+mod replay {
+    use std::io::{self, BufRead, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{action_to_char, set_future, Action, State, H, W};
+
+    /// 1ゲーム分の記録。各ターンの配置位置と選んだ行動
+    #[derive(Clone, Debug, Default)]
+    pub struct Transcript {
+        pub placements: Vec<i64>,
+        pub actions: Vec<Action>,
+    }
+
+    /// 1ターン分の記録。行動後の盤面とスコアを持つ
+    #[derive(Clone, Debug)]
+    pub struct TurnRecord {
+        pub turn: i64,
+        pub color: u8,
+        pub placement: i64,
+        pub action: Action,
+        pub board: [[u8; W]; H],
+        pub score: f64,
+    }
+
+    /// ゲームの進行を1ターンずつ記録する
+    #[derive(Clone, Debug, Default)]
+    pub struct Recorder {
+        future: Vec<u8>,
+        records: Vec<TurnRecord>,
+    }
+
+    impl Recorder {
+        pub fn new(future: &[u8]) -> Self {
+            Self { future: future.to_vec(), records: vec![] }
+        }
+
+        /// `state`は`placement`に置いて`action`で傾けた後の状態
+        pub fn record(&mut self, placement: i64, action: Action, state: &State) {
+            let turn = self.records.len() as i64;
+            self.records.push(TurnRecord {
+                turn,
+                color: self.future.get(turn as usize).copied().unwrap_or(0),
+                placement,
+                action,
+                board: state.board,
+                score: state.get_score(),
+            });
+        }
+
+        pub fn records(&self) -> &[TurnRecord] {
+            &self.records
+        }
+
+        pub fn into_records(self) -> Vec<TurnRecord> {
+            self.records
+        }
+    }
+
+    /// ビジュアライザ向けに、記録をJSONの配列(空白なし)にする
+    pub fn game_log_json(records: &[TurnRecord]) -> String {
+        let mut json = String::from("[");
+        for (i, record) in records.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let board = record
+                .board
+                .iter()
+                .map(|row| format!("[{}]", row.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(",")))
+                .collect::<Vec<_>>()
+                .join(",");
+            json.push_str(&format!(
+                "{{\"turn\":{},\"color\":{},\"placement\":{},\"action\":\"{}\",\"board\":[{}],\"score\":{}}}",
+                record.turn,
+                record.color,
+                record.placement,
+                action_to_char(record.action),
+                board,
+                record.score,
+            ));
+        }
+        json.push(']');
+        json
+    }
+
+    /// 記録された配置を再生しながら毎ターン`solver`を呼び、記録された行動列を再現できるか確かめる
+    /// キャンディの色は`FUTURE_CANDIES`に設定済みのものを使う
+    pub fn verify_replay(transcript: &Transcript, solver: &dyn Fn(&State) -> Action) -> bool {
+        if transcript.placements.len() != transcript.actions.len() {
+            return false;
+        }
+        let mut state = State::new();
+        for (&pt, &recorded) in transcript.placements.iter().zip(&transcript.actions) {
+            if state.is_done() {
+                return false;
+            }
+            state.update(pt);
+            let action = solver(&state);
+            if action != recorded {
+                return false;
+            }
+            state.advance(action);
+        }
+        true
+    }
+
+    /// 記録されたゲームを1ターンずつ端末に色付きで表示する(デバッグ用のツール)
+    /// `step`がtrueなら標準入力でEnterが押されるのを待って1ターンずつ進め、falseなら`delay_ms`ずつ待つ(0なら待たない)
+    /// 標準入力を読むのは`step`のときだけなので、対話的でない環境でも止まらない
+    pub fn replay_to_terminal(transcript: &Transcript, future: &[u8], delay_ms: u64, step: bool) {
+        let stdin = io::stdin();
+        let mut line = String::new();
+        let stdout = io::stdout();
+        replay_frames(&mut stdout.lock(), transcript, future, |_| {
+            if step {
+                line.clear();
+                stdin.lock().read_line(&mut line).unwrap();
+            } else if delay_ms > 0 {
+                thread::sleep(Duration::from_millis(delay_ms));
+            }
+        })
+        .unwrap();
+    }
+
+    /// 記録されたゲームの各ターンの盤面を`out`に書き出す。各フレームの後に`wait`を呼ぶ
+    pub fn replay_frames<O: Write>(out: &mut O, transcript: &Transcript, future: &[u8], mut wait: impl FnMut(i64)) -> io::Result<()> {
+        set_future(future).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut state = State::new();
+        for (turn, (&pt, &action)) in transcript.placements.iter().zip(&transcript.actions).enumerate() {
+            state.update(pt);
+            state.advance(action);
+            writeln!(out, "turn {} action {} score {}", turn + 1, action_to_char(action), state.get_score())?;
+            write!(out, "{}", state.render())?;
+            out.flush()?;
+            wait(turn as i64);
+        }
+        Ok(())
+    }
+}
+
+mod placement {
+    use rand::Rng;
+
+    use super::{State, FUTURE_CANDIES};
+
+    /// シミュレーション中にキャンディをどこへ置くかを決めるモデル
+    pub trait PlacementModel {
+        /// `turn`のキャンディを置く空きマスの番号(1-indexed)を返す。空きマスがなければ0
+        fn place(&self, state: &State, turn: i64, rng: &mut impl Rng) -> i64;
+    }
+
+    /// 公式と同じく空きマスから一様ランダムに選ぶ
+    pub struct RandomPlacement;
+
+    /// こちらが最善の傾け方をしたときのスコアが最小になるマスに置く(最悪ケース)
+    pub struct AdversarialPlacement;
+
+    /// 最善の傾け方をしたときのスコアが、全配置の平均に最も近いマスに置く(典型ケース)
+    pub struct ExpectedPlacement;
+
+    impl PlacementModel for RandomPlacement {
+        fn place(&self, state: &State, _turn: i64, rng: &mut impl Rng) -> i64 {
+            let empty_count = state.empty_count() as i64;
+            if empty_count == 0 {
+                return 0;
+            }
+            rng.gen_range(1..=empty_count)
+        }
+    }
+
+    impl PlacementModel for AdversarialPlacement {
+        fn place(&self, state: &State, turn: i64, _rng: &mut impl Rng) -> i64 {
+            let responses = best_responses(state, turn);
+            let mut worst_pt = 0;
+            let mut worst_score = f64::INFINITY;
+            for (i, &score) in responses.iter().enumerate() {
+                if score < worst_score {
+                    worst_score = score;
+                    worst_pt = i as i64 + 1;
+                }
+            }
+            worst_pt
+        }
+    }
+
+    impl PlacementModel for ExpectedPlacement {
+        fn place(&self, state: &State, turn: i64, _rng: &mut impl Rng) -> i64 {
+            let responses = best_responses(state, turn);
+            if responses.is_empty() {
+                return 0;
+            }
+            let mean = responses.iter().sum::<f64>() / responses.len() as f64;
+            let mut expected_pt = 0;
+            let mut best_diff = f64::INFINITY;
+            for (i, &score) in responses.iter().enumerate() {
+                let diff = (score - mean).abs();
+                if diff < best_diff {
+                    best_diff = diff;
+                    expected_pt = i as i64 + 1;
+                }
+            }
+            expected_pt
+        }
+    }
+
+    /// 各空きマスに`turn`のキャンディを置いたとき、最善の傾け方で得られるスコア
+    fn best_responses(state: &State, turn: i64) -> Vec<f64> {
+        let candy = FUTURE_CANDIES.lock().unwrap()[turn as usize];
+        let empty_count = state.empty_count() as i64;
+        let mut responses = Vec::with_capacity(empty_count as usize);
+        for pt in 1..=empty_count {
+            let mut next_state = state.clone();
+            next_state.place(pt, candy);
+            let best = next_state.score_all_actions().into_iter().fold(f64::NEG_INFINITY, f64::max);
+            responses.push(best);
+        }
+        responses
+    }
+}
+
+mod policy {
+    use super::{State, FEATURE_LEN, LEGAL_ACTIONS};
+
+    /// 盤面から各行動の事前確率を返す方策。学習済みの重みなどを外から差し込むためのもの
+    pub trait Policy {
+        /// `LEGAL_ACTIONS`の順の事前確率(和が1)
+        fn priors(&self, state: &State) -> [f64; 4];
+    }
+
+    /// 各行動で傾けた後の盤面の特徴量(`State::features`)と`weights`の内積をsoftmaxにかけて事前確率とする
+    /// 重みが全て0なら一様になる
+    #[derive(Clone, Debug, Default)]
+    pub struct LinearPolicy {
+        pub weights: [f64; FEATURE_LEN],
+    }
+
+    impl LinearPolicy {
+        pub fn new(weights: [f64; FEATURE_LEN]) -> Self {
+            Self { weights }
+        }
+    }
+
+    impl Policy for LinearPolicy {
+        fn priors(&self, state: &State) -> [f64; 4] {
+            let logits: [f64; 4] = std::array::from_fn(|d| {
+                let mut next = state.clone();
+                next.advance(LEGAL_ACTIONS[d]);
+                next.features().iter().zip(self.weights.iter()).map(|(f, w)| f * w).sum()
+            });
+            let max_logit = logits.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let exps = logits.map(|logit| (logit - max_logit).exp());
+            let total: f64 = exps.iter().sum();
+            exps.map(|e| e / total)
+        }
+    }
+}
+
+mod expectimax {
+    use super::{Action, State, END_TURN, FUTURE_CANDIES, LEGAL_ACTIONS};
+    use std::collections::HashMap;
+
+    /// 残りターン数が`max_remaining`以下なら、全ての配置について期待値を取る完全読みで最善の行動を返す
+    /// 残りが多い場合は計算量が大きすぎるので`None`を返し、呼び出し側はモンテカルロなどにフォールバックする
+    pub fn exact_endgame_action(state: &State, max_remaining: i64) -> Option<Action> {
+        if state.remaining_turns() > max_remaining {
+            return None;
+        }
+        let candies = *FUTURE_CANDIES.lock().unwrap();
+        let mut best_value = f64::NEG_INFINITY;
+        let mut best_action = LEGAL_ACTIONS[0];
+        for &action in LEGAL_ACTIONS.iter() {
+            let mut next = state.clone();
+            next.advance(action);
+            let value = chance_value(&next, &candies);
+            if value > best_value {
+                best_value = value;
+                best_action = action;
+            }
+        }
+        Some(best_action)
+    }
+
+    /// 配置2回分の深さ2のexpectimaxで最善の行動を返す(終盤向け)
+    /// 遷移後の盤面が同じ行動は一度だけ評価する(盤面の変わらない行動も候補に含む)。
+    /// 末端は2回目の配置に対する最善の行動後の`get_score`。配置後の盤面は`canonical_hash`でまとめ、
+    /// 回転・反転で同値になるマスへの配置は一度だけ評価する。
+    /// `future`が足りない場合やゲームが終わる場合はその時点のスコアで打ち切る
+    pub fn expectimax_depth2(state: &State, future: &[u8]) -> Action {
+        let mut memo = HashMap::new();
+        let mut best_value = f64::NEG_INFINITY;
+        let mut best_action = LEGAL_ACTIONS[0];
+        let mut seen = Vec::with_capacity(LEGAL_ACTIONS.len());
+        for &action in LEGAL_ACTIONS.iter() {
+            let mut next = state.clone();
+            next.advance(action);
+            if seen.contains(&next.board) {
+                continue;
+            }
+            seen.push(next.board);
+            let value = bounded_chance_value(&next, future, 2, &mut memo);
+            if value > best_value {
+                best_value = value;
+                best_action = action;
+            }
+        }
+        best_action
+    }
+
+    /// 傾けた後の状態から、残り`depth`回の配置について平均を取った期待値
+    fn bounded_chance_value(state: &State, future: &[u8], depth: usize, memo: &mut HashMap<(u64, usize), f64>) -> f64 {
+        if depth == 0 || state.is_done() || state.turn as usize >= future.len() {
+            return state.get_score();
+        }
+        let candy = future[state.turn as usize];
+        let empty_count = state.empty_count() as i64;
+        let mut total = 0.;
+        for pt in 1..=empty_count {
+            let mut placed = state.clone();
+            placed.place(pt, candy);
+            let key = (placed.canonical_hash(), depth);
+            let value = match memo.get(&key) {
+                Some(&value) => value,
+                None => {
+                    let value = bounded_max_value(&placed, future, depth, memo);
+                    memo.insert(key, value);
+                    value
+                }
+            };
+            total += value;
+        }
+        total / empty_count as f64
+    }
+
+    /// キャンディを置いた後の状態から、盤面の変わる行動の中で最善のものを選んだ期待値
+    fn bounded_max_value(state: &State, future: &[u8], depth: usize, memo: &mut HashMap<(u64, usize), f64>) -> f64 {
+        let mut best_value = f64::NEG_INFINITY;
+        let mut seen = Vec::with_capacity(LEGAL_ACTIONS.len());
+        for &action in LEGAL_ACTIONS.iter() {
+            let mut next = state.clone();
+            next.advance(action);
+            if seen.contains(&next.board) {
+                continue;
+            }
+            seen.push(next.board);
+            best_value = best_value.max(bounded_chance_value(&next, future, depth - 1, memo));
+        }
+        best_value
+    }
+
+    /// キャンディを置いた後の状態から、最善の行動を選び続けたときの最終スコアの期待値
+    fn max_value(state: &State, candies: &[u8; END_TURN as usize]) -> f64 {
+        if state.is_done() {
+            return state.get_score();
+        }
+        let mut best_value = f64::NEG_INFINITY;
+        let mut seen = Vec::with_capacity(LEGAL_ACTIONS.len());
+        for &action in LEGAL_ACTIONS.iter() {
+            let mut next = state.clone();
+            next.advance(action);
+            if seen.contains(&next.board) {
+                continue;
+            }
+            seen.push(next.board);
+            best_value = best_value.max(chance_value(&next, candies));
+        }
+        best_value
+    }
+
+    /// 傾けた後の状態から、次のキャンディの配置について平均を取った最終スコアの期待値
+    fn chance_value(state: &State, candies: &[u8; END_TURN as usize]) -> f64 {
+        if state.is_done() {
+            return state.get_score();
+        }
+        let candy = candies[state.turn as usize];
+        let empty_count = state.empty_count() as i64;
+        let mut total = 0.;
+        for pt in 1..=empty_count {
+            let mut placed = state.clone();
+            placed.place(pt, candy);
+            total += max_value(&placed, candies);
+        }
+        total / empty_count as f64
+    }
+}
+
+mod beam {
+    use super::{rulebase_action, Action, State, StatePool, LEGAL_ACTIONS};
+    use super::time_keeper::TimeKeeper;
+
+    /// 将来の配置として仮定するシミュレーション用乱数表の行
+    const SIMULATION_ROW: usize = 0;
+
+    struct Node {
+        /// `StatePool`内の添字
+        state: usize,
+        actions: Vec<Action>,
+        score: f64,
+    }
+
+    /// ビームサーチで残り全ターンの行動列を求める
+    /// 将来の配置は分からないので、シミュレーション用乱数表の`SIMULATION_ROW`行目の配置を仮定する。
+    /// 展開の途中で時間切れになった場合は、最後に展開し終えた層の最良ノードからルールベースで最後まで進め、
+    /// 常に`END_TURN - base_state.turn`個の行動からなる完全な行動列を返す
+    pub fn beam_search(time_keeper: &TimeKeeper, base_state: &State, beam_width: usize) -> Vec<Action> {
+        let beam_width = beam_width.max(1);
+        let mut pool = StatePool::with_capacity(beam_width * (LEGAL_ACTIONS.len() + 1));
+        let root = pool.acquire(base_state);
+        let mut beam = vec![Node { state: root, actions: vec![], score: base_state.get_score() }];
+        while !pool.get(beam[0].state).is_done() {
+            let mut next_beam = Vec::with_capacity(beam.len() * LEGAL_ACTIONS.len());
+            for node in beam.iter() {
+                if time_keeper.is_time_over() {
+                    return rollout(pool.get(best_node(&beam).state), &best_node(&beam).actions);
+                }
+                for &action in LEGAL_ACTIONS.iter() {
+                    let idx = pool.duplicate(node.state);
+                    let next = pool.get_mut(idx);
+                    next.advance(action);
+                    if !next.is_done() {
+                        next.simulation_update(SIMULATION_ROW);
+                    }
+                    let mut actions = node.actions.clone();
+                    actions.push(action);
+                    next_beam.push(Node { state: idx, actions, score: next.get_score() });
+                }
+            }
+            next_beam.sort_by(|a, b| b.score.total_cmp(&a.score));
+            for node in next_beam.drain(beam_width.min(next_beam.len())..).chain(beam) {
+                pool.release(node.state);
+            }
+            beam = next_beam;
+        }
+        best_node(&beam).actions.clone()
+    }
+
+    fn best_node(beam: &[Node]) -> &Node {
+        beam.iter().max_by(|a, b| a.score.total_cmp(&b.score)).unwrap()
+    }
+
+    /// 途中の行動列の続きをルールベースで最後まで埋める
+    fn rollout(state: &State, actions: &[Action]) -> Vec<Action> {
+        let mut state = state.clone();
+        let mut actions = actions.to_vec();
+        while !state.is_done() {
+            let action = rulebase_action(&state);
+            actions.push(action);
+            state.advance(action);
+            if !state.is_done() {
+                state.simulation_update(SIMULATION_ROW);
+            }
+        }
+        actions
+    }
+}
+
+mod montecalro {
+    use std::time::Instant;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use once_cell::sync::Lazy;
+
+    use crate::{RAND_FOR_ACTION, RANDOM_FOR_SIMULATION, SIMULATION_MAX};
+
+    use super::{State, COLORS, END_TURN, LEGAL_ACTIONS, RULEBASE_TABLE, action_to_char, random_action, rulebase_action, Action};
+    use super::placement::{PlacementModel, RandomPlacement};
+    use super::policy::Policy;
+    use super::time_keeper::TimeKeeper;
+
+    fn playout(state: &mut State, simulation_cnt: usize) -> f64 {
+        while !state.is_done() {
+            state.simulation_update(simulation_cnt);
+            //state.advance(random_action(state));
+            state.advance(rulebase_action(state));
+        }
+        state.get_score()
+    }
+
+    /// ターン`t`の配置位置を`placements[t]`としてプレイアウトする
+    pub fn playout_with_placements(state: &mut State, placements: &[i64]) -> f64 {
+        playout_with_policy(state, placements, rulebase_action)
+    }
+
+    /// `playout_with_placements`と同じだが、行動を`policy`で選ぶ
+    fn playout_with_policy(state: &mut State, placements: &[i64], policy: fn(&State) -> Action) -> f64 {
+        while !state.is_done() {
+            state.update(placements[state.turn as usize]);
+            state.advance(policy(state));
+        }
+        state.get_score()
+    }
+
+    /// 行動`a`と`b`から、同じ配置列(共通乱数)でプレイアウトを`rollouts`回ずつ行い、スコアの差(a - b)の平均を返す
+    /// 配置の乱数を揃えることで、2つの行動の差の推定の分散が小さくなる
+    pub fn paired_rollout_diff(state: &State, a: Action, b: Action, rollouts: usize, seed: u64) -> f64 {
+        if rollouts == 0 {
+            return 0.;
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut placements = [0i64; END_TURN as usize];
+        let mut total_diff = 0.;
+        for _ in 0..rollouts {
+            for (turn, p) in placements.iter_mut().enumerate() {
+                *p = rng.gen_range(1..=END_TURN - turn as i64);
+            }
+            let mut state_a = state.clone();
+            state_a.advance(a);
+            let mut state_b = state.clone();
+            state_b.advance(b);
+            total_diff += playout_with_placements(&mut state_a, &placements) - playout_with_placements(&mut state_b, &placements);
+        }
+        total_diff / rollouts as f64
+    }
+
+    /// 各行動から`oracle_rollouts`回ずつルールベースでプレイアウトした平均を基準に、`chosen`の平均が最善の行動の平均にどれだけ劣るか
+    /// 配置は`seed`から生成し、行動どうしで同じ配置列を使う(共通乱数)。`chosen`が基準で最善なら0
+    pub fn estimated_regret(base_state: &State, chosen: Action, oracle_rollouts: usize, seed: u64) -> f64 {
+        if oracle_rollouts == 0 {
+            return 0.;
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut placements = [0i64; END_TURN as usize];
+        let mut totals = [0.; 4];
+        for _ in 0..oracle_rollouts {
+            for (turn, p) in placements.iter_mut().enumerate() {
+                *p = rng.gen_range(1..=END_TURN - turn as i64);
+            }
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+                let mut state = base_state.clone();
+                state.advance(action);
+                totals[d] += playout_with_placements(&mut state, &placements);
+            }
+        }
+        let means = totals.map(|total| total / oracle_rollouts as f64);
+        let best_mean = means.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        best_mean - means[chosen.to_index()]
+    }
+
+    /// `playout_with_placements`と同じだが、スコアを整数で返す
+    fn playout_int(state: &mut State, placements: &[i64], policy: fn(&State) -> Action) -> i64 {
+        while !state.is_done() {
+            state.update(placements[state.turn as usize]);
+            state.advance(policy(state));
+        }
+        state.get_score_int()
+    }
+
+    /// `playout_with_placements`と同じだが、選んだ行動を`trace`に記録する
+    fn playout_traced(state: &mut State, placements: &[i64], policy: fn(&State) -> Action, trace: &mut Vec<Action>) -> f64 {
+        while !state.is_done() {
+            state.update(placements[state.turn as usize]);
+            let action = policy(state);
+            trace.push(action);
+            state.advance(action);
+        }
+        state.get_score()
+    }
+
+    /// 配置モデル`model`に従ってキャンディを置きながらプレイアウトする
+    pub fn playout_with_model(state: &mut State, model: &impl PlacementModel, rng: &mut impl Rng) -> f64 {
+        while !state.is_done() {
+            let pt = model.place(state, state.turn, rng);
+            state.update(pt);
+            state.advance(rulebase_action(state));
+        }
+        state.get_score()
+    }
+
+    /// プレイアウト中の行動の選び方
+    pub trait PlayoutPolicy {
+        fn choose(&self, state: &State, rng: &mut StdRng) -> Action;
+    }
+
+    /// ルールベース(`rulebase_action`)
+    pub struct RuleBasePolicy;
+
+    /// 一様ランダム
+    pub struct RandomPolicy;
+
+    impl PlayoutPolicy for RuleBasePolicy {
+        fn choose(&self, state: &State, _rng: &mut StdRng) -> Action {
+            rulebase_action(state)
+        }
+    }
+
+    impl PlayoutPolicy for RandomPolicy {
+        fn choose(&self, _state: &State, rng: &mut StdRng) -> Action {
+            LEGAL_ACTIONS[rng.gen_range(0..LEGAL_ACTIONS.len())]
+        }
+    }
+
+    /// `state`から`policy`で`rollouts`回プレイアウトしたときの最終スコアの不偏分散(2回未満なら0)
+    /// 配置は`seed`から一様ランダムに生成する。方策どうしで比べると、ルールベースがどれだけ分散を減らしているかが分かる
+    pub fn playout_variance(state: &State, policy: &impl PlayoutPolicy, rollouts: usize, seed: u64) -> f64 {
+        if rollouts < 2 {
+            return 0.;
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut stats = MonteCarloStats::new();
+        for _ in 0..rollouts {
+            let mut rollout = state.clone();
+            while !rollout.is_done() {
+                rollout.update(rng.gen_range(1..=rollout.empty_count() as i64));
+                let action = policy.choose(&rollout, &mut rng);
+                rollout.advance(action);
+            }
+            stats.push(0, rollout.get_score());
+        }
+        stats.action_variance()[0]
+    }
+
+    /// 盤面を変えなかった行動の回数を数えながらプレイアウトする
+    #[derive(Clone, Debug, Default)]
+    pub struct PlayoutRunner {
+        noop_advances: usize,
+    }
+
+    impl PlayoutRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// `playout`と同じだが、盤面を変えなかった行動を数える
+        pub fn run(&mut self, state: &mut State, simulation_cnt: usize) -> f64 {
+            while !state.is_done() {
+                state.simulation_update(simulation_cnt);
+                let before = state.board;
+                state.advance(rulebase_action(state));
+                if state.board == before {
+                    self.noop_advances += 1;
+                }
+            }
+            state.get_score()
+        }
+
+        /// これまでのプレイアウトで盤面を変えなかった行動の回数
+        /// 多いほどシミュレーションの深さが無駄になっている
+        pub fn count_noop_advances(&self) -> usize {
+            self.noop_advances
+        }
+
+        pub fn reset(&mut self) {
+            self.noop_advances = 0;
+        }
+    }
+
+    /// 評価値が同じ行動の選び方
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum TieBreak {
+        /// `LEGAL_ACTIONS`で最初の行動(Forwardに偏る)
+        #[default]
+        LowestIndex,
+        /// 一様ランダム(`RAND_FOR_ACTION`を使う)
+        Random,
+        /// 盤面を変える行動を優先する
+        PreferEffective,
+        /// 直前に傾けた方向の逆向き(傾けを戻す行動)を避ける
+        PreferNonReversing,
+    }
+
+    /// 探索の設定
+    #[derive(Clone, Debug, Default)]
+    pub struct SearchConfig {
+        /// (実験的) プレイアウトの寄与を、同じ行動の最初のプレイアウトと分岐するまでの長さで重み付けする。
+        /// 行動列の共通部分が長いプレイアウトほど独立な情報が少ないとみなし、
+        /// 重み`1 - 共通部分の長さ / (プレイアウト長 + 1)`で加重平均する。無効なら全て重み1
+        pub divergence_weighting: bool,
+        /// シミュレーション用乱数表を読み始める行をずらす量。seedごとに異なる配置列でプレイアウトする
+        pub seed: u64,
+        /// プレイアウトのスコアを整数のまま合計し、行動の比較も整数で厳密に行う。
+        /// 浮動小数点の丸めに左右されないので、環境によらず同じ行動を選ぶ。有効なら`divergence_weighting`は無視する
+        pub integer_scores: bool,
+        /// プレイアウト中の行動をルールベースではなく一様ランダム(`random_action`)で選ぶ。ルールベースの寄与を測るためのもの
+        pub random_playouts: bool,
+        /// 評価値が同じ行動の選び方
+        pub tie_break: TieBreak,
+        /// 制御変量法で評価値の分散を減らす。制御変量には「傾けた直後に次のキャンディを置いたときのスコア」を使う。
+        /// 次の配置は空きマスから一様なので、その期待値は全ての空きマスに置いてみれば厳密に求まる。
+        /// 各プレイアウトの結果から(制御変量 - 期待値)を引いても期待値は変わらず(不偏)、
+        /// 制御変量と最終スコアの相関が強いほど分散が小さくなる。`integer_scores`が有効なら使わない
+        pub control_variate: bool,
+    }
+
+    /// 各行動のプレイアウト結果の統計
+    /// 平均と分散はWelford法(重み付きの場合はWestの方法)で逐次的に更新する
+    #[derive(Clone, Default)]
+    pub struct MonteCarloStats {
+        count: [usize; 4],
+        weight_sum: [f64; 4],
+        mean: [f64; 4],
+        m2: [f64; 4],
+        min_seen: f64,
+        max_seen: f64,
+        /// `push_int`で追加したスコアの合計
+        score_sum: [i64; 4],
+        /// trueなら`best_action`は`score_sum`から厳密に比較する
+        exact: bool,
+        /// 分位点の推定用に、各行動のプレイアウト結果から最大`RESERVOIR_SIZE`個を一様に抽出したもの
+        reservoir: [Vec<f64>; 4],
+    }
+
+    /// 分位点の推定に保持するプレイアウト結果の数
+    const RESERVOIR_SIZE: usize = 1024;
+
+    /// 標準正規分布の累積分布関数。erfはAbramowitz and Stegun 7.1.26で近似する(誤差1.5e-7以下)
+    fn normal_cdf(z: f64) -> f64 {
+        let x = z.abs() / std::f64::consts::SQRT_2;
+        let t = 1. / (1. + 0.3275911 * x);
+        let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+        let erf = 1. - poly * (-x * x).exp();
+        if z >= 0. { 0.5 * (1. + erf) } else { 0.5 * (1. - erf) }
+    }
+
+    /// reservoir samplingで置き換える位置を決めるためのハッシュ(splitmix64)
+    fn splitmix64(mut x: u64) -> u64 {
+        x = x.wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    }
+
+    impl MonteCarloStats {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// 整数のスコアを`push_int`で追加し、行動を整数の合計から厳密に比較する統計
+        pub fn exact() -> Self {
+            Self { exact: true, ..Self::default() }
+        }
+
+        /// `d`番目の行動のプレイアウト結果を追加する
+        pub fn push(&mut self, d: usize, value: f64) {
+            self.push_weighted(d, value, 1.);
+        }
+
+        /// `d`番目の行動のプレイアウト結果を重み`weight`で追加する
+        pub fn push_weighted(&mut self, d: usize, value: f64, weight: f64) {
+            if self.total_count() == 0 {
+                self.min_seen = value;
+                self.max_seen = value;
+            } else {
+                self.min_seen = self.min_seen.min(value);
+                self.max_seen = self.max_seen.max(value);
+            }
+            self.count[d] += 1;
+            if self.reservoir[d].len() < RESERVOIR_SIZE {
+                self.reservoir[d].push(value);
+            } else {
+                let j = (splitmix64(self.count[d] as u64 ^ ((d as u64) << 56)) % self.count[d] as u64) as usize;
+                if j < RESERVOIR_SIZE {
+                    self.reservoir[d][j] = value;
+                }
+            }
+            self.weight_sum[d] += weight;
+            let delta = value - self.mean[d];
+            self.mean[d] += delta * weight / self.weight_sum[d];
+            self.m2[d] += weight * delta * (value - self.mean[d]);
+        }
+
+        /// `d`番目の行動の整数のプレイアウト結果を追加する
+        pub fn push_int(&mut self, d: usize, value: i64) {
+            self.score_sum[d] += value;
+            self.push(d, value as f64);
+        }
+
+        pub fn action_count(&self) -> [usize; 4] {
+            self.count
+        }
+
+        /// `push_int`で追加したスコアの行動ごとの合計
+        pub fn action_score_sum(&self) -> [i64; 4] {
+            self.score_sum
+        }
+
+        pub fn total_count(&self) -> usize {
+            self.count.iter().sum()
+        }
+
+        pub fn action_mean(&self) -> [f64; 4] {
+            self.mean
+        }
+
+        /// 各行動の不偏分散(重みが全て1ならn-1で割ったもの)。試行が2回未満の行動は0を返す
+        pub fn action_variance(&self) -> [f64; 4] {
+            std::array::from_fn(|d| {
+                if self.count[d] >= 2 {
+                    let n = self.count[d] as f64;
+                    self.m2[d] * n / (self.weight_sum[d] * (n - 1.))
+                } else {
+                    0.
+                }
+            })
+        }
+
+        /// 平均評価値が最大の行動を返す。同値の場合は添字の小さい行動を選ぶ
+        pub fn best_action(&self) -> Action {
+            if self.exact {
+                return self.best_action_exact();
+            }
+            let mut best_score = 0.;
+            let mut best_action_idx = 0usize;
+            for (d, &mean) in self.mean.iter().enumerate() {
+                if mean > best_score {
+                    best_action_idx = d;
+                    best_score = mean;
+                }
+            }
+            LEGAL_ACTIONS[best_action_idx]
+        }
+
+        /// 平均評価値が最大の行動の中から`tie_break`に従って1つ選ぶ。`state`は探索した局面
+        pub fn best_action_with(&self, tie_break: TieBreak, state: &State) -> Action {
+            let best = self.best_action().to_index();
+            let tied: Vec<Action> = (0..LEGAL_ACTIONS.len()).filter(|&d| self.is_tied(d, best)).map(|d| LEGAL_ACTIONS[d]).collect();
+            break_tie(&tied, tie_break, state)
+        }
+
+        /// `d`番目の行動の平均評価値が`best`番目の行動と同じか
+        fn is_tied(&self, d: usize, best: usize) -> bool {
+            if self.exact {
+                let count = self.count[d].max(1) as i128;
+                let best_count = self.count[best].max(1) as i128;
+                self.score_sum[d] as i128 * best_count == self.score_sum[best] as i128 * count
+            } else {
+                self.mean[d] == self.mean[best]
+            }
+        }
+
+        /// 平均`score_sum / count`を通分して整数で比較する。試行のない行動の平均は0とする
+        fn best_action_exact(&self) -> Action {
+            let mean = |d: usize| (self.score_sum[d] as i128, self.count[d].max(1) as i128);
+            let mut best_action_idx = 0usize;
+            for d in 1..LEGAL_ACTIONS.len() {
+                let (sum, count) = mean(d);
+                let (best_sum, best_count) = mean(best_action_idx);
+                if sum * best_count > best_sum * count {
+                    best_action_idx = d;
+                }
+            }
+            LEGAL_ACTIONS[best_action_idx]
+        }
+
+        /// 行動`action`のプレイアウト結果の`p`分位点(`p`は0から1、0.5なら中央値)。間は線形補間する
+        /// 試行が`RESERVOIR_SIZE`回を超えると一様に抽出した標本からの推定になる。試行のない行動は0を返す
+        pub fn action_percentile(&self, action: Action, p: f64) -> f64 {
+            let d = action.to_index();
+            let mut values = self.reservoir[d].clone();
+            if values.is_empty() {
+                return 0.;
+            }
+            values.sort_by(|a, b| a.total_cmp(b));
+            let pos = p.clamp(0., 1.) * (values.len() - 1) as f64;
+            let lower = pos.floor() as usize;
+            let upper = pos.ceil() as usize;
+            values[lower] + (values[upper] - values[lower]) * (pos - lower as f64)
+        }
+
+        /// `p`分位点が最大の行動を返す。`p`を小さくすると悪い場合に強い行動を選ぶ。同値の場合は添字の小さい行動
+        pub fn best_action_by_percentile(&self, p: f64) -> Action {
+            let mut best_action_idx = 0usize;
+            let mut best_score = self.action_percentile(LEGAL_ACTIONS[0], p);
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate().skip(1) {
+                let score = self.action_percentile(action, p);
+                if score > best_score {
+                    best_action_idx = d;
+                    best_score = score;
+                }
+            }
+            LEGAL_ACTIONS[best_action_idx]
+        }
+
+        /// 平均が最大の行動が本当に最善である確からしさ(0.5から1)
+        /// 平均が1位と2位の行動の差を正規分布で近似し、差が正である確率を返す。試行のない行動は比較しない
+        pub fn best_action_confidence(&self) -> f64 {
+            let variance = self.action_variance();
+            let mut arms: Vec<usize> = (0..LEGAL_ACTIONS.len()).filter(|&d| self.count[d] > 0).collect();
+            if arms.len() < 2 {
+                return 0.5;
+            }
+            arms.sort_by(|&a, &b| self.mean[b].total_cmp(&self.mean[a]));
+            let (first, second) = (arms[0], arms[1]);
+            let diff = self.mean[first] - self.mean[second];
+            let se = (variance[first] / self.count[first] as f64 + variance[second] / self.count[second] as f64).sqrt();
+            if se == 0. {
+                return if diff > 0. { 1. } else { 0.5 };
+            }
+            normal_cdf(diff / se)
+        }
+
+        /// これまでに観測したプレイアウト結果の幅(最大値 - 最小値)
+        pub fn score_range(&self) -> f64 {
+            self.max_seen - self.min_seen
+        }
+
+        /// UCB1の探索項に掛ける係数。定数`c`を観測したスコアの幅で拡大縮小する
+        /// (幅が0のうちは`c`をそのまま使う)
+        pub fn exploration_scale(&self, c: f64) -> f64 {
+            let range = self.score_range();
+            if range > 0. { c * range } else { c }
+        }
+
+        /// 試行回数が最も多い行動。同数なら添字の小さい行動
+        pub fn most_visited_action(&self) -> Action {
+            let mut best_action_idx = 0usize;
+            for (d, &count) in self.count.iter().enumerate() {
+                if count > self.count[best_action_idx] {
+                    best_action_idx = d;
+                }
+            }
+            LEGAL_ACTIONS[best_action_idx]
+        }
+
+        /// 試行回数が最も多い行動の中から`tie_break`に従って1つ選ぶ。`state`は探索した局面
+        pub fn most_visited_action_with(&self, tie_break: TieBreak, state: &State) -> Action {
+            let max_count = self.count.iter().copied().max().unwrap_or(0);
+            let tied: Vec<Action> = (0..LEGAL_ACTIONS.len()).filter(|&d| self.count[d] == max_count).map(|d| LEGAL_ACTIONS[d]).collect();
+            break_tie(&tied, tie_break, state)
+        }
+
+        /// 全プレイアウトのうち、最終的に選んだ行動に使われた割合
+        /// 4行動に均等に割り振る原始モンテカルロでは約0.25になる
+        pub fn efficiency(&self) -> f64 {
+            let total = self.total_count();
+            if total == 0 {
+                return 0.;
+            }
+            let best_action_idx = self.best_action().to_index();
+            self.count[best_action_idx] as f64 / total as f64
+        }
+    }
+
+    /// 評価が同じ行動`tied`(`LEGAL_ACTIONS`の順、空でない)から`tie_break`に従って1つ選ぶ
+    fn break_tie(tied: &[Action], tie_break: TieBreak, state: &State) -> Action {
+        let preferred = match tie_break {
+            TieBreak::LowestIndex => None,
+            TieBreak::Random => Some(tied[RAND_FOR_ACTION.lock().unwrap().gen_range(0..tied.len())]),
+            TieBreak::PreferEffective => tied.iter().copied().find(|&action| state.is_effective(action)),
+            TieBreak::PreferNonReversing => {
+                tied.iter().copied().find(|&action| state.last_action() != Some(action.opposite()))
+            }
+        };
+        preferred.unwrap_or(tied[0])
+    }
+
+    /// 各行動の試行回数、平均、平均の95%信頼区間(正規近似)を列を揃えた表にする(デバッグ用)
+    pub fn format_action_table(stats: &MonteCarloStats) -> String {
+        let counts = stats.action_count();
+        let means = stats.action_mean();
+        let variances = stats.action_variance();
+        let mut table = format!("{:<6} {:>8} {:>10} {:>21}\n", "action", "visits", "mean", "95% CI");
+        for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+            let half_width = if counts[d] > 0 { 1.96 * (variances[d] / counts[d] as f64).sqrt() } else { 0. };
+            let interval = format!("[{:.2}, {:.2}]", means[d] - half_width, means[d] + half_width);
+            table.push_str(&format!("{:<6} {:>8} {:>10.2} {:>21}\n", action_to_char(action), counts[d], means[d], interval));
+        }
+        table
+    }
+
+    /// 原始モンテカルロで平均評価値が最大の行動を返す。同値の行動は`tie_break`に従って選ぶ
+    pub fn primitive_monteralro(time_keeper: &TimeKeeper, base_state: &State, tie_break: TieBreak) -> Action {
+        // どの行動でも同じ盤面になるなら探索しても差は出ない
+        if base_state.actions_equivalent() {
+            return LEGAL_ACTIONS[0];
+        }
+        primitive_monteralro_stats(time_keeper, base_state).best_action_with(tie_break, base_state)
+    }
+
+    /// 原始モンテカルロを実行し、各行動のプレイアウト統計を返す
+    pub fn primitive_monteralro_stats(time_keeper: &TimeKeeper, base_state: &State) -> MonteCarloStats {
+        run_primitive(base_state, &SearchConfig::default(), |_| time_keeper.is_time_over())
+    }
+
+    /// 設定`config`に従って原始モンテカルロを行う
+    pub fn montecarlo_with_config(time_keeper: &TimeKeeper, base_state: &State, config: &SearchConfig) -> Action {
+        montecarlo_with_config_stats(time_keeper, base_state, config).best_action_with(config.tie_break, base_state)
+    }
+
+    pub fn montecarlo_with_config_stats(time_keeper: &TimeKeeper, base_state: &State, config: &SearchConfig) -> MonteCarloStats {
+        run_primitive(base_state, config, |_| time_keeper.is_time_over())
+    }
+
+    /// 時間ではなく各行動ちょうど`iters_per_action`回(最大`SIMULATION_MAX`回)のプレイアウトで原始モンテカルロを行う
+    /// 配置はシード固定のシミュレーション用乱数表から取るので、同じ回数なら常に同じ行動を返す(`TieBreak::Random`を除く)
+    pub fn montecarlo_fixed_iters(base_state: &State, iters_per_action: usize, tie_break: TieBreak) -> Action {
+        run_primitive(base_state, &SearchConfig::default(), |simulation_cnt| simulation_cnt >= iters_per_action).best_action_with(tie_break, base_state)
+    }
+
+    /// `montecarlo_fixed_iters`の設定を指定できる版。各行動の統計を返す
+    pub fn montecarlo_fixed_iters_stats(base_state: &State, config: &SearchConfig, iters_per_action: usize) -> MonteCarloStats {
+        run_primitive(base_state, config, |simulation_cnt| simulation_cnt >= iters_per_action)
+    }
+
+    /// 各行動`iters[i]`回のプレイアウトでの原始モンテカルロが選ぶ行動を並べて返す(`seed`は`SearchConfig::seed`)
+    /// 少ない回数で行動が変わらなくなるなら`SIMULATION_MAX`を減らせる
+    pub fn convergence_trace(state: &State, iters: &[usize], seed: u64) -> Vec<Action> {
+        let config = SearchConfig { seed, ..SearchConfig::default() };
+        iters
+            .iter()
+            .map(|&iters_per_action| run_primitive(state, &config, |simulation_cnt| simulation_cnt >= iters_per_action).best_action())
+            .collect()
+    }
+
+    /// `auto_budget`で1反復の時間を測るのに使う反復回数
+    const CALIBRATION_ITERS: usize = 16;
+    /// `auto_budget`が見積もりとは別に必ず残す余裕(msec)
+    pub const MIN_SAFETY_MARGIN_MS: u64 = 50;
+
+    /// 全体の制限時間`target_total_ms`に対して、`TimeKeeper::new`に渡す安全な持ち時間(msec)を返す
+    /// 起動時の準備(シミュレーション用乱数表の生成)の時間と1反復(4行動分のプレイアウト)の時間をこの場で測り、
+    /// 毎ターン最大1反復ずつ締め切りを超えうる分と`MIN_SAFETY_MARGIN_MS`を差し引く。
+    /// 乱数表の生成前に呼ぶとその時間も測れる
+    pub fn auto_budget(target_total_ms: u64) -> u64 {
+        let start = Instant::now();
+        Lazy::force(&RANDOM_FOR_SIMULATION);
+        let startup = start.elapsed();
+
+        // 最も長いターン0からのプレイアウトで測る。FUTURE_CANDIESの設定によらないよう、色は固定の循環にしてルールベースで傾ける
+        let calibration_start = Instant::now();
+        let mut total_score = 0.;
+        for i in 0..CALIBRATION_ITERS {
+            for _ in LEGAL_ACTIONS.iter() {
+                let mut state = State::new();
+                while !state.is_done() {
+                    let turn = state.turn as usize;
+                    let pt = RANDOM_FOR_SIMULATION.lock().unwrap()[i][turn];
+                    state.place(pt, (turn % COLORS) as u8 + 1);
+                    state.advance(RULEBASE_TABLE[turn % COLORS][(turn + 1) % COLORS]);
+                }
+                total_score += state.get_score();
+            }
+        }
+        std::hint::black_box(total_score);
+        let calibration = calibration_start.elapsed();
+        let iteration_cost = calibration / CALIBRATION_ITERS as u32;
+
+        let overshoot = iteration_cost * END_TURN as u32;
+        let margin_ms = (startup + calibration + overshoot).as_millis() as u64 + MIN_SAFETY_MARGIN_MS;
+        target_total_ms.saturating_sub(margin_ms)
+    }
+
+    /// 持ち時間`budgets_ms`のそれぞれで原始モンテカルロを1ターン分だけ実行し、選ばれた行動を並べて返す
+    /// 少ない持ち時間で行動が変わらなくなるなら、それ以上時間をかけても無駄になっている
+    pub fn stability_curve(state: &State, budgets_ms: &[u64]) -> Vec<Action> {
+        budgets_ms
+            .iter()
+            .map(|&budget| {
+                let time_keeper = TimeKeeper::new(budget, 1);
+                primitive_monteralro(&time_keeper, state, TieBreak::default())
+            })
+            .collect()
+    }
+
+    /// 共通乱数(CRN): 1回の反復では、4つの行動のプレイアウトがすべて同じ配置列(シミュレーション用乱数表の同じ行)を使う。
+    /// 行は反復の最初に1度だけ写し取って4つのプレイアウトに渡すので、行動の間で配置列が食い違うことはなく、
+    /// 行動どうしの評価値の差には配置の運の違いが入らない(比較の分散が小さくなる)。
+    /// 根の配置は探索前に済んでいるので、行のうちターン`base_state.turn + 1`以降の部分が使われる
+    fn run_primitive(base_state: &State, config: &SearchConfig, mut should_stop: impl FnMut(usize) -> bool) -> MonteCarloStats {
+        let mut stats = if config.integer_scores { MonteCarloStats::exact() } else { MonteCarloStats::new() };
+        let mut references: [Vec<Action>; 4] = Default::default();
+        let mut trace = vec![];
+        let row_offset = (config.seed % SIMULATION_MAX as u64) as usize;
+        let policy: fn(&State) -> Action = if config.random_playouts { random_action } else { rulebase_action };
+        let expected_first: [f64; 4] = if config.control_variate {
+            std::array::from_fn(|d| {
+                let mut state = base_state.clone();
+                state.advance(LEGAL_ACTIONS[d]);
+                expected_first_placement_score(&state)
+            })
+        } else {
+            [0.; 4]
+        };
+        for simulation_cnt in 0..SIMULATION_MAX {
+            if should_stop(simulation_cnt) {
+                break;
+            }
+            let row = (simulation_cnt + row_offset) % SIMULATION_MAX;
+            let placements = RANDOM_FOR_SIMULATION.lock().unwrap()[row].clone();
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+                let mut state = base_state.clone();
+                state.advance(action);
+                if config.integer_scores {
+                    stats.push_int(d, playout_int(&mut state, &placements, policy));
+                    continue;
+                }
+                let correction = if config.control_variate {
+                    expected_first[d] - first_placement_score(&state, &placements)
+                } else {
+                    0.
+                };
+                if !config.divergence_weighting {
+                    stats.push(d, playout_with_policy(&mut state, &placements, policy) + correction);
+                    continue;
+                }
+                trace.clear();
+                let value = playout_traced(&mut state, &placements, policy, &mut trace) + correction;
+                if simulation_cnt == 0 {
+                    references[d] = trace.clone();
+                    stats.push(d, value);
+                } else {
+                    stats.push_weighted(d, value, divergence_weight(&references[d], &trace));
+                }
+            }
+        }
+        stats
+    }
+
+    /// 傾けた後の状態`state`に、配置列`placements`の次のキャンディを置いた直後のスコア(制御変量)
+    fn first_placement_score(state: &State, placements: &[i64]) -> f64 {
+        if state.is_done() {
+            return state.get_score();
+        }
+        let mut next = state.clone();
+        next.update(placements[state.turn as usize]);
+        next.get_score()
+    }
+
+    /// `first_placement_score`の期待値。全ての空きマスに置いた場合の平均
+    fn expected_first_placement_score(state: &State) -> f64 {
+        let empty_count = state.empty_count() as i64;
+        if state.is_done() || empty_count == 0 {
+            return state.get_score();
+        }
+        let total: f64 = (1..=empty_count)
+            .map(|pt| {
+                let mut next = state.clone();
+                next.update(pt);
+                next.get_score()
+            })
+            .sum();
+        total / empty_count as f64
+    }
+
+    /// UCB1でプレイアウトを割り振るモンテカルロ。試行回数が最も多い行動を返す(同数なら`tie_break`に従う)
+    /// 探索項の係数は`c`に観測したスコアの幅を掛けたもので、スコアの大きさによらず探索の度合いを保つ
+    pub fn ucb1_montecarlo_scaled(time_keeper: &TimeKeeper, base_state: &State, c: f64, tie_break: TieBreak) -> Action {
+        ucb1_montecarlo_scaled_stats(time_keeper, base_state, c).most_visited_action_with(tie_break, base_state)
+    }
+
+    pub fn ucb1_montecarlo_scaled_stats(time_keeper: &TimeKeeper, base_state: &State, c: f64) -> MonteCarloStats {
+        let mut stats = MonteCarloStats::new();
+        while !time_keeper.is_time_over() {
+            let counts = stats.action_count();
+            let d = match counts.iter().position(|&n| n == 0) {
+                Some(d) => d,
+                None => {
+                    let log_total = (stats.total_count() as f64).ln();
+                    let scale = stats.exploration_scale(c);
+                    let means = stats.action_mean();
+                    let ucb = |d: usize| means[d] + scale * (log_total / counts[d] as f64).sqrt();
+                    (0..LEGAL_ACTIONS.len()).fold(0, |best, d| if ucb(d) > ucb(best) { d } else { best })
+                }
+            };
+            let mut state = base_state.clone();
+            state.advance(LEGAL_ACTIONS[d]);
+            stats.push(d, playout(&mut state, counts[d] % SIMULATION_MAX));
+        }
+        stats
+    }
+
+    /// `puct_action`の探索項の係数
+    pub const C_PUCT: f64 = 1.0;
+
+    /// 方策`policy`の事前確率を使い、PUCT(`Q + c_puct * P * sqrt(N) / (1 + n)`)でプレイアウトを割り振るモンテカルロ。
+    /// 試行回数が最も多い行動を返す(同数なら`tie_break`に従う)
+    pub fn puct_action(time_keeper: &TimeKeeper, root: &State, policy: &impl Policy, tie_break: TieBreak) -> Action {
+        puct_stats(time_keeper, root, policy, C_PUCT).most_visited_action_with(tie_break, root)
+    }
+
+    /// `puct_action`の統計版。UCB1と同じく、係数`c_puct`には観測したスコアの幅を掛ける
+    pub fn puct_stats(time_keeper: &TimeKeeper, root: &State, policy: &impl Policy, c_puct: f64) -> MonteCarloStats {
+        let priors = policy.priors(root);
+        let mut stats = MonteCarloStats::new();
+        while !time_keeper.is_time_over() {
+            let counts = stats.action_count();
+            let sqrt_total = (stats.total_count() as f64).sqrt();
+            let scale = stats.exploration_scale(c_puct);
+            let means = stats.action_mean();
+            let puct = |d: usize| means[d] + scale * priors[d] * sqrt_total / (1 + counts[d]) as f64;
+            let d = (0..LEGAL_ACTIONS.len()).fold(0, |best, d| if puct(d) > puct(best) { d } else { best });
+            let mut state = root.clone();
+            state.advance(LEGAL_ACTIONS[d]);
+            stats.push(d, playout(&mut state, counts[d] % SIMULATION_MAX));
+        }
+        stats
+    }
+
+    /// プレイアウトを打ち切った局面の評価関数
+    pub trait Evaluator {
+        fn evaluate(&self, state: &State) -> f64;
+    }
+
+    /// その時点の盤面のスコアをそのまま使う。最後まで進めた局面では真の評価値になる
+    pub struct TerminalEvaluator;
+
+    impl Evaluator for TerminalEvaluator {
+        fn evaluate(&self, state: &State) -> f64 {
+            state.get_score()
+        }
+    }
+
+    /// プレイアウトを`depth`ターンで打ち切り、`evaluator`で評価する原始モンテカルロ
+    /// 1回のプレイアウトが短くなるぶん回数を増やせるが、評価値は最終スコアではなく`evaluator`の値なので、
+    /// 打ち切り時点の評価が最終スコアと食い違う分だけ偏りが生じる。`depth >= 残りターン数`なら通常のプレイアウトと同じ。
+    /// 評価値が同じ行動は`tie_break`に従って選ぶ
+    pub fn truncated_montecarlo(time_keeper: &TimeKeeper, base_state: &State, depth: usize, evaluator: &impl Evaluator, tie_break: TieBreak) -> Action {
+        let mut stats = MonteCarloStats::new();
+        for simulation_cnt in 0..SIMULATION_MAX {
+            if time_keeper.is_time_over() {
+                break;
+            }
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+                let mut state = base_state.clone();
+                state.advance(action);
+                for _ in 0..depth {
+                    if state.is_done() {
+                        break;
+                    }
+                    state.simulation_update(simulation_cnt);
+                    state.advance(rulebase_action(&state));
+                }
+                stats.push(d, evaluator.evaluate(&state));
+            }
+        }
+        stats.best_action_with(tie_break, base_state)
+    }
+
+    /// ターンの持ち時間を`seeds`の数で等分し、seedごとに原始モンテカルロを行って多数決で行動を選ぶ
+    /// 票数が同じ行動の間では、各探索で選ばれたときの平均評価値の合計が大きい方を選ぶ。
+    /// 各探索での同値と、票数も評価値の合計も同じ行動の間は`tie_break`に従う
+    pub fn ensemble_action(time_keeper: &TimeKeeper, base_state: &State, seeds: &[u64], tie_break: TieBreak) -> Action {
+        if seeds.is_empty() {
+            return primitive_monteralro(time_keeper, base_state, tie_break);
+        }
+        let start_time = Instant::now();
+        let slice = time_keeper.turn_threshold() / seeds.len() as u32;
+        let mut votes = [0usize; 4];
+        let mut value_sums = [0.; 4];
+        for (k, &seed) in seeds.iter().enumerate() {
+            let deadline = start_time + slice * (k as u32 + 1);
+            let config = SearchConfig { seed, ..Default::default() };
+            let stats = run_primitive(base_state, &config, |_| time_keeper.is_time_over() || Instant::now() >= deadline);
+            let action = stats.best_action_with(tie_break, base_state);
+            let d = action.to_index();
+            votes[d] += 1;
+            value_sums[d] += stats.action_mean()[d];
+        }
+        let mut best_action_idx = 0usize;
+        for d in 1..LEGAL_ACTIONS.len() {
+            if (votes[d], value_sums[d]) > (votes[best_action_idx], value_sums[best_action_idx]) {
+                best_action_idx = d;
+            }
+        }
+        let best = (votes[best_action_idx], value_sums[best_action_idx]);
+        let tied: Vec<Action> = (0..LEGAL_ACTIONS.len()).filter(|&d| (votes[d], value_sums[d]) == best).map(|d| LEGAL_ACTIONS[d]).collect();
+        break_tie(&tied, tie_break, base_state)
+    }
+
+    /// 基準の行動列`reference`と共通する先頭部分が長いほど小さくなる重み(0, 1]
+    fn divergence_weight(reference: &[Action], trace: &[Action]) -> f64 {
+        let common = reference.iter().zip(trace).take_while(|(a, b)| a == b).count();
+        1. - common as f64 / (trace.len() + 1) as f64
+    }
+
+    /// 配置モデル`model`を仮定して原始モンテカルロを行う。評価値が同じ行動は`tie_break`に従って選ぶ
+    pub fn montecarlo_with_model(time_keeper: &TimeKeeper, base_state: &State, model: &impl PlacementModel, rng: &mut impl Rng, tie_break: TieBreak) -> Action {
+        let mut stats = MonteCarloStats::new();
+        while !time_keeper.is_time_over() {
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+                let mut state = base_state.clone();
+                state.advance(action);
+                stats.push(d, playout_with_model(&mut state, model, rng));
+            }
+        }
+        stats.best_action_with(tie_break, base_state)
+    }
+
+    /// `pessimistic_playout`で敵が選べる配置列の数
+    pub const PESSIMISTIC_CANDIDATES: usize = 4;
+
+    /// 一様ランダムな配置列を`candidates`本引き、それぞれルールベースの行動で最後まで遊んだスコアの最小値
+    /// 敵はプレイアウトと同じ行動の選び方を前提に最悪の配置列を選ぶので、`candidates`本の最小値の期待値は
+    /// 1本の期待値、つまり一様ランダムな配置での期待値以下になる
+    pub fn pessimistic_playout(state: &State, candidates: usize, rng: &mut impl Rng) -> f64 {
+        (0..candidates)
+            .map(|_| playout_with_model(&mut state.clone(), &RandomPlacement, rng))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// `pessimistic_playout`の平均で行動を選ぶ。最悪の配置を仮定する保守的な評価で、一様ランダムな配置での期待値より低めに見積もる。
+    /// 時間切れまで全行動を1回ずつ評価する周回を繰り返す(最初の周回は必ず終える)。評価値が同じ行動は`tie_break`に従って選ぶ
+    pub fn pessimistic_montecarlo(time_keeper: &TimeKeeper, state: &State, rng: &mut impl Rng, tie_break: TieBreak) -> Action {
+        let mut stats = MonteCarloStats::new();
+        loop {
+            for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+                let mut next = state.clone();
+                next.advance(action);
+                stats.push(d, pessimistic_playout(&next, PESSIMISTIC_CANDIDATES, rng));
+            }
+            if time_keeper.is_time_over() {
+                break;
+            }
+        }
+        stats.best_action_with(tie_break, state)
+    }
+
+    /// 累積評価値をsoftmax分布に変換し、確率的に行動を選ぶ
+    /// 有限でない評価値(NaNや±inf)の行動は確率0とし、確率の合計が正にならなければargmaxを返す
+    /// * `weights` - 各行動の累積評価値
+    /// * `temperature` - 温度。0に近いほどargmaxと同じ振る舞いになる
+    pub fn sample_action_softmax(weights: &[f64; 4], temperature: f64, rng: &mut impl Rng) -> Action {
+        let max_weight = weights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let best_action_idx = weights.iter().position(|&w| w == max_weight).unwrap_or(0);
+        if temperature <= f64::EPSILON {
+            return LEGAL_ACTIONS[best_action_idx];
+        }
+        let probs = weights.map(|w| if w.is_finite() { ((w - max_weight) / temperature).exp() } else { 0. });
+        let total: f64 = probs.iter().sum();
+        if !total.is_finite() || total <= 0. {
+            return LEGAL_ACTIONS[best_action_idx];
+        }
+        let mut r = rng.gen_range(0.0..total);
+        for (d, p) in probs.iter().enumerate() {
+            if r < *p {
+                return LEGAL_ACTIONS[d];
+            }
+            r -= p;
+        }
+        LEGAL_ACTIONS[best_action_idx]
+    }
+}
+
+fn main() {
+    Lazy::force(&RANDOM_FOR_SIMULATION);
+
+    let mut judge = judge::StdioJudge::new();
+    let result = if std::env::args().any(|arg| arg == judge::DYNAMIC_INPUT_ARG) {
+        judge.read_input_dynamic().and_then(|input| judge::play_dynamic(&mut judge, &input, 1950))
+    } else {
+        judge.read_future().and_then(|future| judge::play_from(&mut judge, State::new(), &future, 1950))
+    };
+    if let Err(message) = result {
+        eprintln!("error: {}", message);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, MutexGuard};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    /// `FUTURE_CANDIES`などのグローバルな状態を使うテストを直列化する
+    static GLOBALS: Mutex<()> = Mutex::new(());
+
+    fn lock_globals() -> MutexGuard<'static, ()> {
+        GLOBALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// 上の行から順に`rows`を並べた盤面。'.'は空き、数字は色で、足りない行は空きで埋める
+    fn board(rows: &[&str], turn: i64) -> State {
+        let mut state = State::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                state.board[y][x] = c.to_digit(10).unwrap_or(0) as u8;
+            }
+        }
+        state.turn = turn;
+        state
+    }
+
+    /// `Scenario::generate(seed)`をルールベースで`turns`ターン進め、次のキャンディを置いた(傾ける前の)状態
+    /// `FUTURE_CANDIES`を書き換えるので、呼び出し側は`lock_globals`しておく
+    fn midgame(seed: u64, turns: i64) -> State {
+        let scenario = offline::Scenario::generate(seed);
+        set_future(scenario.future()).unwrap();
+        let mut state = State::new();
+        for &pt in &scenario.placements()[..turns as usize] {
+            state.update(pt);
+            state.advance(rulebase_action(&state));
+        }
+        if !state.is_done() {
+            state.update(scenario.placements()[turns as usize]);
+        }
+        state
+    }
+
+    #[test]
+    fn softmax_at_low_temperature_is_argmax() {
+        let weights = [1.0, 5.0, 4.9, -2.0];
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert!(matches!(montecalro::sample_action_softmax(&weights, 1e-9, &mut rng), Action::Back));
+        }
+    }
+
+    #[test]
+    fn softmax_survives_non_finite_weights() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let nan = [1., f64::NAN, 3., 2.];
+        let all_neg_inf = [f64::NEG_INFINITY; 4];
+        let pos_inf = [1., 2., f64::INFINITY, 3.];
+        for _ in 0..100 {
+            assert!(!matches!(montecalro::sample_action_softmax(&nan, 1., &mut rng), Action::Back));
+            assert!(matches!(montecalro::sample_action_softmax(&all_neg_inf, 1., &mut rng), Action::Forward));
+            assert!(matches!(montecalro::sample_action_softmax(&pos_inf, 1., &mut rng), Action::Left));
+        }
+    }
+
+    #[test]
+    fn canonical_hash_is_rotation_invariant() {
+        let state = board(&["12.3", ".1..", "..2", "3"], 5);
+        let mut rotated = board(&[], 5);
+        rotated.board = std::array::from_fn(|y| std::array::from_fn(|x| state.board[H - 1 - x][y]));
+        assert_ne!(rotated.board, state.board);
+        assert_eq!(rotated.canonical_hash(), state.canonical_hash());
+    }
+
+    #[test]
+    fn throttled_time_check_is_never_early_and_barely_late() {
+        let mut time_keeper = time_keeper::TimeKeeper::new(20, 1);
+        time_keeper.set_turn(0);
+        let start = std::time::Instant::now();
+        let mut deadline_seen = None;
+        let mut iter = 0;
+        loop {
+            let throttled = time_keeper.is_time_over_throttled(iter);
+            if time_keeper.is_time_over() && deadline_seen.is_none() {
+                deadline_seen = Some(std::time::Instant::now());
+            }
+            if throttled {
+                // 間引いた判定が先に締め切りを報告することはない
+                let deadline_seen = deadline_seen.expect("reported over before the deadline");
+                assert!(deadline_seen.elapsed() < std::time::Duration::from_millis(5));
+                break;
+            }
+            assert!(start.elapsed() < std::time::Duration::from_secs(1));
+            iter += 1;
+        }
+    }
+
+    #[test]
+    fn action_variance_matches_textbook_sample_variance() {
+        let mut stats = montecalro::MonteCarloStats::new();
+        for value in [2., 4., 4., 4., 5., 5., 7., 9.] {
+            stats.push(1, value);
+        }
+        stats.push(2, 3.);
+        let variance = stats.action_variance();
+        assert!((variance[1] - 32. / 7.).abs() < 1e-9);
+        assert_eq!(variance[0], 0.);
+        assert_eq!(variance[2], 0.);
+    }
+
+    #[test]
+    fn advance_all_advances_turn_per_action() {
+        let mut state = board(&["1", "2"], 10);
+        state.advance_all(&[Action::Forward, Action::Left, Action::Back, Action::Right, Action::Forward]);
+        assert_eq!(state.turn, 15);
+    }
+
+    #[test]
+    fn official_placements_stay_within_remaining_empties() {
+        for seed in 0..20 {
+            let placements: Vec<i64> = official_placement_stream(seed).collect();
+            assert_eq!(placements.len(), END_TURN as usize);
+            for (turn, &pt) in placements.iter().enumerate() {
+                assert!((1..=END_TURN - turn as i64).contains(&pt));
+            }
+        }
+    }
+
+    #[test]
+    fn score_all_actions_matches_score_after() {
+        let _guard = lock_globals();
+        for (seed, turns) in [(0, 10), (1, 50), (2, 90)] {
+            let state = midgame(seed, turns);
+            let expected = LEGAL_ACTIONS.map(|action| state.score_after(action));
+            assert_eq!(state.score_all_actions(), expected);
+        }
+    }
+
+    #[test]
+    fn placement_models_pick_valid_and_worst_cells() {
+        let _guard = lock_globals();
+        set_future(&[1; END_TURN as usize]).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut rows = vec!["3333333333"; H];
+        rows[0] = ".133333333";
+        rows[H - 1] = "333333333.";
+        let state = board(&rows, 97);
+        use placement::PlacementModel;
+        for _ in 0..100 {
+            assert!((1..=2).contains(&placement::RandomPlacement.place(&state, 97, &mut rng)));
+        }
+        // 左上なら既存の1とつながるが、右下では孤立する
+        assert_eq!(placement::AdversarialPlacement.place(&state, 97, &mut rng), 2);
+    }
+
+    #[test]
+    fn verify_replay_detects_modified_transcript() {
+        let _guard = lock_globals();
+        for (t, candy) in FUTURE_CANDIES.lock().unwrap().iter_mut().enumerate() {
+            *candy = (t % COLORS) as u8 + 1;
+        }
+        let placements: Vec<i64> = official_placement_stream(3).collect();
+        let mut transcript = replay::Transcript { placements: placements.clone(), actions: vec![] };
+        let mut state = State::new();
+        for &pt in &placements {
+            state.update(pt);
+            let action = rulebase_action(&state);
+            transcript.actions.push(action);
+            state.advance(action);
+        }
+        assert!(replay::verify_replay(&transcript, &rulebase_action));
+        transcript.actions[40] = if transcript.actions[40] == Action::Left { Action::Right } else { Action::Left };
+        assert!(!replay::verify_replay(&transcript, &rulebase_action));
+    }
+
+    #[test]
+    fn smallest_group_and_singletons() {
+        let state = board(&["1.22"], 0);
+        assert_eq!(state.smallest_group_size(), 1);
+        assert_eq!(state.singleton_count(), 1);
     }
-    let now_candy_idx = candies[state.turn as usize] as usize - 1;
-    let next_candy_idx = candies[state.turn as usize + 1] as usize - 1;
-    return rule[now_candy_idx][next_candy_idx];
-}
 
-mod montecalro {
-    use crate::SIMULATION_MAX;
+    #[test]
+    fn shared_time_keeper_flips_in_all_threads() {
+        let time_keeper = time_keeper::TimeKeeper::new(200, 1);
+        time_keeper.set_turn(0);
+        let time_keeper = time_keeper.shared();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let time_keeper = time_keeper.clone();
+                std::thread::spawn(move || {
+                    let before = time_keeper.is_time_over();
+                    std::thread::sleep(std::time::Duration::from_millis(150));
+                    (before, time_keeper.is_time_over())
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), (false, true));
+        }
+    }
 
-    use super::{State, LEGAL_ACTIONS, random_action, rulebase_action, Action};
-    use super::time_keeper::TimeKeeper;
+    #[test]
+    fn fixed_iteration_search_is_reproducible() {
+        let _guard = lock_globals();
+        let state = midgame(4, 60);
+        let first = montecalro::montecarlo_fixed_iters(&state, 30, montecalro::TieBreak::default());
+        assert_eq!(montecalro::montecarlo_fixed_iters(&state, 30, montecalro::TieBreak::default()), first);
+    }
 
-    fn playout(state: &mut State, simulation_cnt: usize) -> f64 {
-        while !state.is_done() {
-            state.simulation_update(simulation_cnt);
-            //state.advance(random_action(state));
-            state.advance(rulebase_action(state));
+    #[test]
+    fn read_input_file_round_trips() {
+        let future: Vec<u8> = (0..END_TURN).map(|t| (t % COLORS as i64) as u8 + 1).collect();
+        let placements: Vec<i64> = official_placement_stream(5).collect();
+        let mut text = future.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(" ");
+        text.push('\n');
+        for pt in &placements {
+            text.push_str(&format!("{}\n", pt));
         }
-        state.get_score()
+        let path = std::env::temp_dir().join(format!("ahc015_input_{}.txt", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let read = offline::read_input_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), (future, placements));
     }
 
-    pub fn primitive_monteralro(time_keeper: &TimeKeeper, base_state: &State) -> Action {
-        let mut w = [0.; LEGAL_ACTIONS.len()];
-        for simulation_cnt in 0..SIMULATION_MAX {
-            if time_keeper.is_time_over() {
-                break;
+    #[test]
+    fn offline_summary_covers_every_turn() {
+        let _guard = lock_globals();
+        let summary = offline::run_offline(&offline::Scenario::generate(6), 100);
+        assert_eq!(summary.turns, END_TURN as usize);
+        assert!(summary.to_string().contains("turns=100"));
+    }
+
+    #[test]
+    fn custom_rulebase_table_is_used() {
+        let _guard = lock_globals();
+        let state = midgame(7, 50);
+        let table = [[Action::Left; COLORS]; COLORS];
+        assert_eq!(rulebase_action_with(&state, &table), Action::Left);
+    }
+
+    #[test]
+    fn unweighted_search_matches_plain_playout_average() {
+        let _guard = lock_globals();
+        let state = midgame(8, 70);
+        let config = montecalro::SearchConfig { divergence_weighting: false, ..Default::default() };
+        let stats = montecalro::montecarlo_fixed_iters_stats(&state, &config, 20);
+        for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+            let mut total = 0.;
+            for row in 0..20 {
+                let placements = RANDOM_FOR_SIMULATION.lock().unwrap()[row].clone();
+                let mut next = state.clone();
+                next.advance(action);
+                while !next.is_done() {
+                    next.update(placements[next.turn as usize]);
+                    next.advance(rulebase_action(&next));
+                }
+                total += next.get_score();
+            }
+            assert_eq!(stats.action_count()[d], 20);
+            assert!((stats.action_mean()[d] - total / 20.).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn last_placement_reports_the_placed_cell() {
+        let _guard = lock_globals();
+        set_future(&[1; END_TURN as usize]).unwrap();
+        let mut state = board(&["11"], 0);
+        assert_eq!(state.last_placement(), None);
+        state.update(5);
+        assert_eq!(state.last_placement(), Some((0, 6)));
+        state.reset();
+        assert_eq!(state.last_placement(), None);
+    }
+
+    #[test]
+    fn greedy_against_itself_always_ties() {
+        let _guard = lock_globals();
+        let result = offline::compare_solvers(&GreedySolver, &GreedySolver, &[0, 1, 2], 0);
+        assert_eq!((result.wins, result.losses, result.ties), (0, 0, 3));
+        assert_eq!(result.mean_diff, 0.);
+        assert_eq!(result.scores_a, result.scores_b);
+    }
+
+    #[test]
+    fn beam_search_under_tiny_budget_returns_full_game() {
+        let _guard = lock_globals();
+        let state = midgame(9, 0);
+        let time_keeper = time_keeper::TimeKeeper::new(0, 1);
+        let actions = beam::beam_search(&time_keeper, &state, 8);
+        assert_eq!(actions.len(), END_TURN as usize);
+    }
+
+    #[test]
+    fn placement_gain_is_larger_next_to_same_color() {
+        let _guard = lock_globals();
+        set_future(&[1; END_TURN as usize]).unwrap();
+        let state = board(&["111", "", "", "", "", "", "", "", "", "2"], 4);
+        let gains = state.placement_gains();
+        let gain_at = |cell| gains.iter().find(|(c, _)| *c == cell).unwrap().1;
+        assert_eq!(gain_at((0, 3)), 16. - 9.);
+        assert_eq!(gain_at((5, 5)), 1.);
+        assert!(gain_at((0, 3)) > gain_at((5, 5)));
+        assert!(board(&["1"], END_TURN).placement_gains().is_empty());
+    }
+
+    #[test]
+    fn corrupted_board_fails_invariant_check() {
+        let mut state = State::new();
+        state.board[2][3] = COLORS as u8 + 1;
+        let err = state.check_invariants().err().unwrap();
+        assert!(err.contains("cell (2, 3)"), "{}", err);
+        assert!(board(&[], END_TURN + 1).check_invariants().is_err());
+    }
+
+    #[test]
+    fn tilting_a_finished_state_keeps_working() {
+        let mut state = board(&["1.2", "", "3"], END_TURN);
+        assert_eq!(state.effective_actions(), LEGAL_ACTIONS.to_vec());
+        assert!(!state.actions_equivalent());
+        assert_eq!(state.forced_action(), None);
+        state.advance(Action::Back);
+        assert_eq!(state.turn, END_TURN + 1);
+    }
+
+    #[test]
+    fn offline_writer_emits_the_same_bytes_as_interactive() {
+        let actions = [Action::Forward, Action::Left, Action::Right, Action::Back, Action::Left];
+        let mut interactive = ActionWriter::interactive(Vec::new());
+        let mut offline = ActionWriter::offline(std::io::BufWriter::new(Vec::new()));
+        for &action in actions.iter() {
+            interactive.write_action(action).unwrap();
+            offline.write_action(action).unwrap();
+        }
+        let interactive = interactive.finish().unwrap();
+        let offline = offline.finish().unwrap().into_inner().unwrap();
+        assert_eq!(interactive, b"F\nL\nR\nB\nL\n");
+        assert_eq!(offline, interactive);
+    }
+
+    #[test]
+    fn successors_cover_every_effective_action_and_cell() {
+        let _guard = lock_globals();
+        set_future(&[1; END_TURN as usize]).unwrap();
+        let state = board(&["1", "2"], 10);
+        let effective = state.effective_actions();
+        assert_eq!(effective, vec![Action::Back, Action::Right]);
+        let successors = state.successors();
+        assert_eq!(successors.len(), effective.len() * state.empty_count());
+    }
+
+    #[test]
+    fn exact_endgame_maximizes_final_score_with_one_turn_left() {
+        let _guard = lock_globals();
+        for seed in 0..5 {
+            let state = midgame(seed, END_TURN - 1);
+            assert_eq!(END_TURN - state.turn, 1);
+            let action = expectimax::exact_endgame_action(&state, 1).unwrap();
+            let best = state.score_all_actions().into_iter().fold(f64::NEG_INFINITY, f64::max);
+            assert_eq!(state.score_after(action), best);
+        }
+        assert!(expectimax::exact_endgame_action(&midgame(0, 50), 1).is_none());
+    }
+
+    #[test]
+    fn uniform_search_efficiency_is_a_quarter() {
+        let _guard = lock_globals();
+        let state = midgame(1, 80);
+        let stats = montecalro::montecarlo_fixed_iters_stats(&state, &montecalro::SearchConfig::default(), 50);
+        assert!((stats.efficiency() - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn state_pool_reuses_released_slots() {
+        let mut pool = StatePool::new();
+        let state = board(&["12"], 3);
+        for _ in 0..100 {
+            let a = pool.acquire(&state);
+            let b = pool.duplicate(a);
+            pool.get_mut(b).advance(Action::Back);
+            pool.release(a);
+            pool.release(b);
+        }
+        assert_eq!(pool.allocated(), 2);
+    }
+
+    #[test]
+    fn approximate_score_at_stride_one_is_exact() {
+        let _guard = lock_globals();
+        for (seed, turns) in [(0, 30), (1, 60), (2, 99)] {
+            let state = midgame(seed, turns);
+            assert_eq!(state.get_score_approx(1), state.get_score());
+        }
+    }
+
+    #[test]
+    fn paired_rollout_diff_of_an_action_with_itself_is_zero() {
+        let _guard = lock_globals();
+        let state = midgame(3, 70);
+        for &action in LEGAL_ACTIONS.iter() {
+            assert_eq!(montecalro::paired_rollout_diff(&state, action, action, 10, 7), 0.);
+        }
+    }
+
+    #[test]
+    fn stratified_table_stays_within_remaining_empties() {
+        let table = generate_simulation_table(TableStrategy::Stratified);
+        assert_eq!(table.len(), SIMULATION_MAX);
+        for row in table.iter() {
+            for (turn, &pt) in row.iter().enumerate() {
+                assert!((1..=END_TURN - turn as i64).contains(&pt));
+            }
+        }
+    }
+
+    #[test]
+    fn scenario_rejects_wrong_length_future() {
+        let placements: Vec<i64> = official_placement_stream(0).collect();
+        let err = offline::Scenario::new(vec![1; 99], placements.clone()).unwrap_err();
+        assert!(err.contains("100"), "{}", err);
+        assert!(offline::Scenario::new(vec![1; END_TURN as usize], placements).is_ok());
+    }
+
+    #[test]
+    fn io_reserve_reduces_turn_threshold() {
+        let plain = time_keeper::TimeKeeper::new(1000, 10);
+        let mut reserved = time_keeper::TimeKeeper::new(1000, 10);
+        reserved.set_expected_io_ms(5);
+        let diff = plain.turn_threshold().as_secs_f64() - reserved.turn_threshold().as_secs_f64();
+        assert!((diff - 0.005).abs() < 0.0005, "{}", diff);
+    }
+
+    #[test]
+    fn replay_frames_writes_one_frame_per_turn() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(2);
+        set_future(scenario.future()).unwrap();
+        let mut transcript = replay::Transcript { placements: scenario.placements().to_vec(), actions: vec![] };
+        let mut state = State::new();
+        for &pt in scenario.placements() {
+            state.update(pt);
+            let action = rulebase_action(&state);
+            transcript.actions.push(action);
+            state.advance(action);
+        }
+        let mut out = Vec::new();
+        let mut waits = 0;
+        replay::replay_frames(&mut out, &transcript, scenario.future(), |_| waits += 1).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().filter(|line| line.starts_with("turn ")).count(), END_TURN as usize);
+        assert_eq!(waits, END_TURN);
+    }
+
+    #[test]
+    fn sortedness_of_grouped_and_fragmented_boards() {
+        assert_eq!(board(&["1112", "1122", "3"], 7).sortedness(), 1.);
+        let checkered = ["1212121212", "2121212121"].repeat(H / 2);
+        let fragmented = board(&checkered, END_TURN).sortedness();
+        assert!(fragmented < 0.05, "{}", fragmented);
+    }
+
+    #[test]
+    fn ensemble_with_one_seed_matches_plain_search() {
+        let _guard = lock_globals();
+        // 残り1ターンならプレイアウトの結果は傾けた直後のスコアで決まり、反復回数によらない
+        let state = midgame(5, END_TURN - 1);
+        let time_keeper = time_keeper::TimeKeeper::new(20, 1);
+        let plain = montecalro::primitive_monteralro(&time_keeper, &state, montecalro::TieBreak::default());
+        let time_keeper = time_keeper::TimeKeeper::new(20, 1);
+        assert_eq!(montecalro::ensemble_action(&time_keeper, &state, &[0], montecalro::TieBreak::default()), plain);
+        let time_keeper = time_keeper::TimeKeeper::new(20, 1);
+        let action = montecalro::ensemble_action(&time_keeper, &state, &[1, 2, 3], montecalro::TieBreak::default());
+        assert!(LEGAL_ACTIONS.contains(&action));
+    }
+
+    #[test]
+    fn dominant_color_and_its_corner() {
+        let mut rows = vec![""; H];
+        rows[0] = "1";
+        rows[7] = "........22";
+        rows[8] = ".......222";
+        rows[9] = "3.....2222";
+        let state = board(&rows, 10);
+        assert_eq!(state.dominant_color(), 2);
+        assert_eq!(state.best_corner_for(2), (9, 9));
+    }
+
+    #[test]
+    fn full_depth_truncated_search_matches_primitive_search() {
+        let _guard = lock_globals();
+        // 終盤なら制限時間内にシミュレーション用乱数表を全て使い切るので、両者は同じ配置列で評価する
+        let state = midgame(6, END_TURN - 2);
+        let time_keeper = time_keeper::TimeKeeper::new(20_000, 1);
+        let truncated = montecalro::truncated_montecarlo(&time_keeper, &state, END_TURN as usize, &montecalro::TerminalEvaluator, montecalro::TieBreak::default());
+        let time_keeper = time_keeper::TimeKeeper::new(20_000, 1);
+        assert_eq!(truncated, montecalro::primitive_monteralro(&time_keeper, &state, montecalro::TieBreak::default()));
+    }
+
+    #[test]
+    fn rows_round_trip() {
+        let state = board(&["1.2", "", "..33"], 12);
+        let restored = State::from_rows(&state.to_rows(), 12).unwrap();
+        assert_eq!(restored.to_rows(), state.to_rows());
+        assert_eq!(restored.turn, 12);
+        assert!(State::from_rows(&state.to_rows()[1..], 12).is_err());
+    }
+
+    #[test]
+    fn wall_blocks_candies_in_its_column() {
+        let mut state = board(&["1", "2"], 0);
+        state.set_wall(5, 0, true);
+        state.advance(Action::Back);
+        let rows = state.to_rows();
+        assert_eq!((rows[3][0], rows[4][0]), (1, 2));
+        assert_eq!(rows[H - 1][0], 0);
+    }
+
+    #[test]
+    fn walls_are_part_of_the_position() {
+        let mut state = board(&["1"], 1);
+        state.set_wall(0, 1, true);
+        // 壁だけ鏡映しにしない盤面は同値ではない
+        let mut half_mirrored = board(&[".........1"], 1);
+        half_mirrored.set_wall(0, 1, true);
+        assert_ne!(half_mirrored.canonical_hash(), state.canonical_hash());
+        let mut mirrored = board(&[".........1"], 1);
+        mirrored.set_wall(0, W - 2, true);
+        assert_eq!(mirrored.canonical_hash(), state.canonical_hash());
+        assert_eq!(state.hamming_distance(&board(&["1"], 1)), 1);
+    }
+
+    #[test]
+    fn turn_and_remaining_turns() {
+        let mut state = State::new();
+        assert_eq!((state.turn(), state.remaining_turns()), (0, 100));
+        state.advance(Action::Forward);
+        assert_eq!((state.turn(), state.remaining_turns()), (1, 99));
+    }
+
+    #[test]
+    fn heatmap_gives_group_size_to_each_cell() {
+        let state = board(&["111.2"], 5);
+        let heatmap = state.score_heatmap();
+        assert_eq!(&heatmap[0][..5], &[3., 3., 3., 0., 1.]);
+        assert_eq!(heatmap.iter().flatten().sum::<f64>(), state.get_score());
+    }
+
+    #[test]
+    fn diagonal_neighbors_join_only_with_eight_connectivity() {
+        let state = board(&["1", ".1"], 2);
+        assert_eq!(state.get_score_conn(Connectivity::Four), 2.);
+        assert_eq!(state.get_score_conn(Connectivity::Eight), 4.);
+        assert_eq!(state.get_score_conn(Connectivity::Four), state.get_score());
+    }
+
+    #[test]
+    fn rulebase_score_matches_manual_playthrough() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(11);
+        let score = offline::rulebase_score(&scenario);
+        set_future(scenario.future()).unwrap();
+        let mut state = State::new();
+        for &pt in scenario.placements() {
+            state.update(pt);
+            state.advance(rulebase_action(&state));
+        }
+        assert_eq!(score, state.get_score());
+    }
+
+    #[test]
+    fn exploration_scale_grows_with_score_range() {
+        let mut wide = montecalro::MonteCarloStats::new();
+        let mut tight = montecalro::MonteCarloStats::new();
+        for (d, (w, t)) in [(100., 1000.), (2000., 1010.), (900., 1005.), (1500., 1002.)].into_iter().enumerate() {
+            wide.push(d, w);
+            tight.push(d, t);
+        }
+        assert!(wide.exploration_scale(1.) > tight.exploration_scale(1.));
+        assert_eq!(tight.exploration_scale(1.), 10.);
+    }
+
+    #[test]
+    fn resumed_game_plays_only_remaining_turns() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(12);
+        set_future(scenario.future()).unwrap();
+        let mut state = State::new();
+        for &pt in &scenario.placements()[..97] {
+            state.update(pt);
+            state.advance(rulebase_action(&state));
+        }
+        let mut judge = judge::MockJudge::new(scenario.placements().to_vec());
+        let score = judge::play_from(&mut judge, state, scenario.future(), 30).unwrap();
+        assert_eq!(judge.actions().len(), 3);
+        assert!(score > 0.);
+    }
+
+    #[test]
+    fn playout_runner_counts_noop_advances() {
+        let _guard = lock_globals();
+        set_future(&[2; END_TURN as usize]).unwrap();
+        // 空きマスが1つだけなので、最後のキャンディで埋まった盤面はどう傾けても変わらない
+        let mut rows = vec!["1111111111"; H];
+        rows[4] = "11111.1111";
+        let mut state = board(&rows, END_TURN - 1);
+        let mut runner = montecalro::PlayoutRunner::new();
+        runner.run(&mut state, 0);
+        assert_eq!(runner.count_noop_advances(), 1);
+        runner.reset();
+        assert_eq!(runner.count_noop_advances(), 0);
+    }
+
+    #[test]
+    fn placement_density_with_ten_empties_is_uniform() {
+        let mut rows = vec!["1111111111"; H];
+        rows[0] = "..........";
+        let state = board(&rows, 90);
+        let density = state.placement_density(1);
+        assert!(density[0].iter().all(|&p| (p - 0.1).abs() < 1e-12));
+        assert!(density[1..].iter().flatten().all(|&p| p == 0.));
+    }
+
+    /// `game_log_json`の出力の括弧の対応を確かめ、最上位の配列の要素数を返す
+    fn count_json_array_objects(json: &str) -> Option<usize> {
+        let mut depth = 0usize;
+        let mut objects = 0;
+        let mut in_string = false;
+        for c in json.chars() {
+            match c {
+                '"' => in_string = !in_string,
+                _ if in_string => {}
+                '[' | '{' => {
+                    if depth == 1 && c == '{' {
+                        objects += 1;
+                    }
+                    depth += 1;
+                }
+                ']' | '}' => depth = depth.checked_sub(1)?,
+                _ => {}
             }
+        }
+        (depth == 0 && !in_string).then_some(objects)
+    }
+
+    #[test]
+    fn game_log_json_has_one_object_per_turn() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(13);
+        set_future(scenario.future()).unwrap();
+        let mut recorder = replay::Recorder::new(scenario.future());
+        let mut state = State::new();
+        for &pt in scenario.placements() {
+            state.update(pt);
+            let action = rulebase_action(&state);
+            state.advance(action);
+            recorder.record(pt, action, &state);
+        }
+        let json = replay::game_log_json(recorder.records());
+        assert_eq!(count_json_array_objects(&json), Some(END_TURN as usize));
+    }
+
+    #[test]
+    fn stability_curve_with_identical_budgets_is_constant() {
+        let _guard = lock_globals();
+        let state = midgame(14, END_TURN - 1);
+        let curve = montecalro::stability_curve(&state, &[5, 5, 5]);
+        assert_eq!(curve.len(), 3);
+        assert!(curve.iter().all(|&action| action == curve[0]));
+    }
+
+    #[test]
+    fn overshoot_warning_fires_only_beyond_tolerance() {
+        use std::time::Duration;
+        let budget = Duration::from_millis(10);
+        let tolerance = Duration::from_millis(1);
+        let warning = time_keeper::overshoot_warning(42, Duration::from_millis(15), budget, tolerance).unwrap();
+        assert!(warning.contains("42"), "{}", warning);
+        assert_eq!(time_keeper::overshoot_warning(42, Duration::from_micros(10_500), budget, tolerance), None);
+        assert_eq!(time_keeper::overshoot_warning(42, Duration::from_millis(5), budget, tolerance), None);
+    }
+
+    #[test]
+    fn shortest_path_finds_a_single_tilt() {
+        let state = board(&["", "1.2", "", "..3"], END_TURN - 1);
+        let mut target = state.clone();
+        target.advance(Action::Forward);
+        assert_eq!(state.shortest_path_to(&target, 3), Some(vec![Action::Forward]));
+        let mut two_away = target.clone();
+        two_away.advance(Action::Right);
+        assert_eq!(state.shortest_path_to(&two_away, 3).map(|path| path.len()), Some(2));
+        assert_eq!(state.shortest_path_to(&state, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn dfs_and_bfs_scores_agree_on_random_boards() {
+        use rand::Rng;
+        let mut rng = StdRng::seed_from_u64(15);
+        for _ in 0..200 {
+            let fill = rng.gen_range(0.0..=1.0);
+            let rows: Vec<Vec<u8>> = (0..H)
+                .map(|_| (0..W).map(|_| if rng.gen_bool(fill) { rng.gen_range(1..=COLORS as u8) } else { 0 }).collect())
+                .collect();
+            let state = State::from_rows(&rows, 0).unwrap();
+            assert_eq!(state.get_score_dfs(), state.get_score());
+        }
+    }
+
+    #[test]
+    fn integer_accumulation_matches_float_argmax() {
+        let _guard = lock_globals();
+        for (seed, turns) in [(16, 40), (17, 70), (18, 90)] {
+            let state = midgame(seed, turns);
+            let float = montecalro::montecarlo_fixed_iters_stats(&state, &montecalro::SearchConfig::default(), 20);
+            let config = montecalro::SearchConfig { integer_scores: true, ..Default::default() };
+            let integer = montecalro::montecarlo_fixed_iters_stats(&state, &config, 20);
+            assert_eq!(integer.best_action(), float.best_action());
             for d in 0..LEGAL_ACTIONS.len() {
-                let mut state = base_state.clone();
-                state.advance(LEGAL_ACTIONS[d]);
-                w[d] += playout(&mut state, simulation_cnt);
+                assert!((integer.action_score_sum()[d] as f64 / 20. - float.action_mean()[d]).abs() < 1e-6);
             }
         }
-        let mut best_score = 0.;
-        let mut best_action_idx = 0usize;
-        for (d, wd) in w.iter().enumerate() {
-            if *wd > best_score { 
-                best_action_idx = d;
-                best_score = *wd;
+    }
+
+    #[test]
+    fn all_tilts_of_an_empty_board_are_equivalent() {
+        assert!(State::new().actions_equivalent());
+        assert!(!board(&["1.2"], 2).actions_equivalent());
+    }
+
+    #[test]
+    fn action_percentile_finds_the_median() {
+        let mut stats = montecalro::MonteCarloStats::new();
+        for value in [9., 1., 7., 3., 5.] {
+            stats.push(Action::Left.to_index(), value);
+        }
+        for value in [4., 1., 3., 2.] {
+            stats.push(Action::Right.to_index(), value);
+        }
+        assert_eq!(stats.action_percentile(Action::Left, 0.5), 5.);
+        assert_eq!(stats.action_percentile(Action::Right, 0.5), 2.5);
+        assert_eq!(stats.action_percentile(Action::Left, 0.), 1.);
+        assert_eq!(stats.action_percentile(Action::Forward, 0.5), 0.);
+    }
+
+    #[test]
+    fn turns_until_full_counts_empties() {
+        let mut rows = vec!["1111111111"; H];
+        rows[3] = "1..1.1..1.";
+        rows[8] = "1111111111";
+        let state = board(&rows, 93);
+        assert_eq!(state.turns_until_full(), 6);
+        let mut rows = vec!["2222222222"; H];
+        rows[0] = "2.2.2.2.2.";
+        rows[9] = "222222..22";
+        let state = board(&rows, 93);
+        assert_eq!(state.turns_until_full(), 7);
+    }
+
+    #[test]
+    fn compact_column_matches_forward_tilt_of_that_column() {
+        let state = board(&["", "1.", "", "23", ".", "31", "", ".2"], 20);
+        let mut compacted = state.clone();
+        compacted.compact_column(0, true);
+        assert_eq!(compacted.turn(), 20);
+        let mut tilted = state.clone();
+        tilted.advance(Action::Forward);
+        let (compacted, tilted, original) = (compacted.to_rows(), tilted.to_rows(), state.to_rows());
+        for y in 0..H {
+            assert_eq!(compacted[y][0], tilted[y][0]);
+            assert_eq!(compacted[y][1..], original[y][1..]);
+        }
+    }
+
+    #[test]
+    fn adaptive_solver_switches_on_budget() {
+        let _guard = lock_globals();
+        let state = midgame(19, 60);
+        let time_keeper = time_keeper::TimeKeeper::new(0, 1);
+        assert_eq!(adaptive_solver(&time_keeper, &state), greedy_action(&state));
+        // モンテカルロなら持ち時間を使い切るまで探索する
+        let time_keeper = time_keeper::TimeKeeper::new(100, 1);
+        let start = std::time::Instant::now();
+        adaptive_solver(&time_keeper, &state);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(20));
+    }
+
+    #[test]
+    fn confidence_is_high_for_a_clear_winner_and_half_for_a_tie() {
+        let mut clear = montecalro::MonteCarloStats::new();
+        let mut tied = montecalro::MonteCarloStats::new();
+        for i in 0..50 {
+            let noise = (i % 5) as f64;
+            clear.push(0, 1000. + noise);
+            tied.push(0, 500. + noise);
+            for d in 1..4 {
+                clear.push(d, 100. + noise);
+                tied.push(d, 500. + noise);
             }
         }
-        LEGAL_ACTIONS[best_action_idx]
+        assert!(clear.best_action_confidence() > 0.999);
+        assert!((tied.best_action_confidence() - 0.5).abs() < 1e-9);
     }
-}
 
-fn main() {
-    {
-        let mut rand_for_simulation = RANDOM_FOR_SIMULATION.lock().unwrap();
+    #[test]
+    fn auto_budget_leaves_a_safety_margin() {
+        let _guard = lock_globals();
+        let budget = montecalro::auto_budget(1950);
+        assert!(budget + montecalro::MIN_SAFETY_MARGIN_MS <= 1950, "{}", budget);
+        assert_eq!(montecalro::auto_budget(0), 0);
+    }
+
+    #[test]
+    fn color_imbalance_extremes() {
+        assert_eq!(board(&["1111", "11"], 6).color_imbalance(), 1.);
+        assert!(board(&["123", "321"], 6).color_imbalance().abs() < 1e-12);
+    }
+
+    #[test]
+    fn fuzzed_tilts_keep_invariants() {
+        offline::fuzz_invariants(1, 200, 100).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "candy counts changed")]
+    fn conservation_check_fires_on_a_corrupted_tilt() {
+        let before = board(&["12", "3"], 4);
+        let mut after = before.clone();
+        after.advance(Action::Back);
+        // 重力が壊れてキャンディが1つ消えた場合を模す
+        let mut rows = after.to_rows();
+        let cell = rows.iter_mut().flatten().find(|c| **c != 0).unwrap();
+        *cell = 0;
+        State::from_rows(&rows, after.turn()).unwrap().assert_conservation(&before);
+    }
+
+    #[test]
+    fn remaining_optimal_score_equals_full_future_maximum() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(20);
+        let state = midgame(20, 50);
+        assert_eq!(state.remaining_optimal_score(scenario.future()), scenario.best_possible_score());
+    }
+
+    #[test]
+    fn dynamic_input_dispatches_by_board_size() {
+        let colors: Vec<String> = (0..25).map(|i| (i % 3 + 1).to_string()).collect();
+        let text = format!("5 5 25 3\n{}\n", colors.join(" "));
+        let header = offline::GameHeader { h: 5, w: 5, turns: 25, colors: 3 };
+        let (parsed, future) = offline::parse_input_with_header(&text).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(future[..4], [1, 2, 3, 1]);
+        assert_eq!(offline::parse_input_dynamic(&text), Ok(offline::DynamicInput::Custom(header, future)));
+        let standard = format!("10 10 100 3\n{}\n", vec!["2"; 100].join(" "));
+        assert_eq!(offline::parse_input_dynamic(&standard), Ok(offline::DynamicInput::Standard(vec![2; 100])));
+        assert!(offline::parse_input_dynamic("2 2 4 2\n1 2 3 1\n").is_err());
+        assert!(offline::parse_input_dynamic("2 2 5 2\n1 2 1 2 1\n").is_err());
+    }
+
+    #[test]
+    fn small_board_game_plays_to_the_end() {
+        let mut state = dynamic::DynState::new(5, 5);
+        state.place(7, 1).unwrap();
+        state.advance(Action::Right);
+        assert_eq!((state.get(1, 4), state.empty_count(), state.turn()), (1, 24, 1));
+        assert!(state.place(25, 2).is_err());
+
+        let future: Vec<u8> = (0..25).map(|i| (i % 3 + 1) as u8).collect();
+        let input = offline::DynamicInput::new(offline::GameHeader { h: 5, w: 5, turns: 25, colors: 3 }, future).unwrap();
+        let placements: Vec<i64> = (0..25).map(|t| t * 7 % (25 - t) + 1).collect();
+        let mut judge = judge::MockJudge::new(placements);
+        let score = judge::play_dynamic(&mut judge, &input, 100).unwrap();
+        assert_eq!(judge.actions().len(), 25);
+        // 25個すべてが孤立していれば25、色ごとに1つにまとまれば9^2 + 8^2 + 8^2
+        assert!((25. ..=209.).contains(&score), "{}", score);
+    }
+
+    #[test]
+    fn hamming_distance_counts_differing_cells() {
+        let state = board(&["12", "3"], 3);
+        assert_eq!(state.hamming_distance(&state.clone()), 0);
+        assert_eq!(state.hamming_distance(&board(&["12", "2"], 3)), 1);
+    }
+
+    #[test]
+    fn one_iteration_uses_the_same_placements_for_all_actions() {
+        let _guard = lock_globals();
+        let state = midgame(21, 60);
+        let config = montecalro::SearchConfig { seed: 5, ..Default::default() };
+        let stats = montecalro::montecarlo_fixed_iters_stats(&state, &config, 1);
+        let placements = RANDOM_FOR_SIMULATION.lock().unwrap()[5].clone();
+        for (d, &action) in LEGAL_ACTIONS.iter().enumerate() {
+            let mut next = state.clone();
+            next.advance(action);
+            assert_eq!(stats.action_mean()[d], montecalro::playout_with_placements(&mut next, &placements));
+        }
+    }
+
+    #[test]
+    fn expected_random_score_converges_with_more_samples() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(22);
+        let spread = |samples| {
+            let estimates: Vec<f64> = (0..8).map(|seed| offline::expected_random_score(&scenario, samples, seed)).collect();
+            assert!(estimates.iter().all(|e| e.is_finite() && *e > 0.));
+            let mean = estimates.iter().sum::<f64>() / estimates.len() as f64;
+            estimates.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / estimates.len() as f64
+        };
+        assert!(spread(32) < spread(2));
+    }
+
+    #[test]
+    fn random_playouts_differ_from_rulebase_playouts() {
+        let _guard = lock_globals();
+        let state = midgame(23, 50);
+        let rulebase = montecalro::montecarlo_fixed_iters_stats(&state, &montecalro::SearchConfig::default(), 2);
+        let config = montecalro::SearchConfig { random_playouts: true, ..Default::default() };
+        *RAND_FOR_ACTION.lock().unwrap() = StdRng::seed_from_u64(0);
+        let random = montecalro::montecarlo_fixed_iters_stats(&state, &config, 2);
+        assert_ne!(random.action_mean(), rulebase.action_mean());
+        *RAND_FOR_ACTION.lock().unwrap() = StdRng::seed_from_u64(0);
+        assert_eq!(montecalro::montecarlo_fixed_iters_stats(&state, &config, 2).action_mean(), random.action_mean());
+    }
+
+    #[test]
+    fn action_index_roundtrip() {
+        for (i, &action) in LEGAL_ACTIONS.iter().enumerate() {
+            assert_eq!(action.to_index(), i);
+            assert_eq!(Action::from_index(action.to_index()), Some(action));
+        }
+        assert_eq!(Action::from_index(4), None);
+    }
+
+    #[test]
+    fn zero_weight_linear_policy_is_uniform() {
+        use policy::Policy;
+        let _guard = lock_globals();
+        let state = midgame(3, 30);
+        for prior in policy::LinearPolicy::default().priors(&state) {
+            assert!((prior - 0.25).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn peaked_policy_concentrates_puct_visits() {
+        struct Peaked(usize);
+        impl policy::Policy for Peaked {
+            fn priors(&self, _state: &State) -> [f64; 4] {
+                std::array::from_fn(|d| if d == self.0 { 0.97 } else { 0.01 })
+            }
+        }
+        let _guard = lock_globals();
+        let state = midgame(5, 40);
+        let visit_share = |search: &dyn Fn(&time_keeper::TimeKeeper) -> montecalro::MonteCarloStats| {
+            let stats = search(&time_keeper::TimeKeeper::new(50, 1));
+            let counts = stats.action_count();
+            (0..4).map(|d| counts[d] as f64 / stats.total_count() as f64).collect::<Vec<_>>()
+        };
+        let uniform = visit_share(&|tk| montecalro::puct_stats(tk, &state, &policy::LinearPolicy::default(), montecalro::C_PUCT));
+        let favored = (0..4).fold(0, |least, d| if uniform[d] < uniform[least] { d } else { least });
+        let peaked = visit_share(&|tk| montecalro::puct_stats(tk, &state, &Peaked(favored), montecalro::C_PUCT));
+        assert!(peaked[favored] > uniform[favored], "peaked {:?} vs uniform {:?}", peaked, uniform);
+    }
+
+    #[test]
+    fn rng_checkpoint_reproduces_draws() {
+        let _guard = lock_globals();
+        let checkpoint = rng_checkpoint();
+        let draw = || (0..5).map(|_| RAND_FOR_ACTION.lock().unwrap().gen_range(0..u64::MAX)).collect::<Vec<_>>();
+        let first = draw();
+        rng_restore(checkpoint);
+        assert_eq!(draw(), first);
+    }
+
+    #[test]
+    fn score_progression_ends_at_final_score() {
+        let _guard = lock_globals();
+        let summary = offline::run_offline(&offline::Scenario::generate(4), 100);
+        assert_eq!(summary.score_progression.len(), END_TURN as usize);
+        assert_eq!(summary.score_progression.last().copied(), Some(summary.score));
+    }
+
+    #[test]
+    fn best_placement_cell_joins_large_group() {
+        let state = board(&["", "", "", "", "", "....111...", "....111..."], 6);
+        let (y, x) = state.best_placement_cell(1).unwrap();
+        assert_ne!((y, x), (0, 0));
+        let adjacent = [(0, 1), (2, 1), (1, 0), (1, 2)].iter().any(|&(dy, dx)| {
+            let (ny, nx) = ((y + dy).wrapping_sub(1), (x + dx).wrapping_sub(1));
+            ny < H && nx < W && state.board[ny][nx] == 1
+        });
+        assert!(adjacent, "chose {:?}", (y, x));
+        assert_eq!(board(&["1111111111"; 10], 100).best_placement_cell(1), None);
+    }
+
+    #[test]
+    fn prefer_effective_breaks_ties_toward_effective_action() {
+        use montecalro::TieBreak;
+        // 左上に1つだけなので、Forward(上)とLeft(左)は盤面を変えない
+        let state = board(&["1"], 1);
+        let mut stats = montecalro::MonteCarloStats::new();
+        for d in 0..LEGAL_ACTIONS.len() {
+            stats.push(d, 1.);
+        }
+        assert_eq!(stats.best_action_with(TieBreak::LowestIndex, &state), Action::Forward);
+        assert_eq!(stats.best_action_with(TieBreak::PreferEffective, &state), Action::Back);
+        assert_eq!(stats.most_visited_action_with(TieBreak::PreferEffective, &state), Action::Back);
+    }
+
+    #[test]
+    fn remaining_searches_follow_tie_break() {
+        use montecalro::TieBreak;
+        let _guard = lock_globals();
+        // 終局後の盤面なので、どの行動もスコアは同じ1になる
+        let state = board(&["1"], END_TURN);
+        let time_keeper = || TimeKeeper::new(500, END_TURN);
         let mut rng = StdRng::seed_from_u64(0);
-        for simulation_cnt in 0..SIMULATION_MAX {
-            for turn in 0..END_TURN {
-                let remain_turn = END_TURN - turn;
-                let p = rng.gen_range(1..=remain_turn);
-                rand_for_simulation[simulation_cnt][turn as usize] = p;
+        for (tie_break, expected) in [(TieBreak::LowestIndex, Action::Forward), (TieBreak::PreferEffective, Action::Back)] {
+            assert_eq!(montecalro::montecarlo_fixed_iters(&state, 3, tie_break), expected);
+            assert_eq!(montecalro::truncated_montecarlo(&time_keeper(), &state, 5, &montecalro::TerminalEvaluator, tie_break), expected);
+            assert_eq!(montecalro::montecarlo_with_model(&time_keeper(), &state, &placement::RandomPlacement, &mut rng, tie_break), expected);
+            assert_eq!(montecalro::pessimistic_montecarlo(&time_keeper(), &state, &mut rng, tie_break), expected);
+        }
+    }
+
+    #[test]
+    fn disabled_control_variate_matches_baseline() {
+        let _guard = lock_globals();
+        let state = midgame(8, 60);
+        let baseline = montecalro::montecarlo_fixed_iters_stats(&state, &montecalro::SearchConfig::default(), 5);
+        let config = montecalro::SearchConfig { control_variate: false, ..Default::default() };
+        let disabled = montecalro::montecarlo_fixed_iters_stats(&state, &config, 5);
+        assert_eq!(disabled.action_count(), baseline.action_count());
+        assert_eq!(disabled.action_mean(), baseline.action_mean());
+        assert_eq!(disabled.action_variance(), baseline.action_variance());
+        assert_eq!(montecalro::montecarlo_fixed_iters(&state, 5, montecalro::TieBreak::default()), disabled.best_action());
+        let config = montecalro::SearchConfig { control_variate: true, ..Default::default() };
+        assert_ne!(montecalro::montecarlo_fixed_iters_stats(&state, &config, 5).action_mean(), baseline.action_mean());
+    }
+
+    #[test]
+    fn short_future_is_rejected_with_a_clear_error() {
+        let _guard = lock_globals();
+        set_future(&[1; END_TURN as usize]).unwrap();
+        let err = set_future(&[2; 50]).unwrap_err();
+        assert!(err.contains("100") && err.contains("50"), "{}", err);
+        // 失敗したときは以前の未来がそのまま残る
+        assert!(FUTURE_CANDIES.lock().unwrap().iter().all(|&candy| candy == 1));
+        let transcript = replay::Transcript { placements: vec![1; 150], actions: vec![Action::Forward; 150] };
+        let err = replay::replay_frames(&mut Vec::new(), &transcript, &[1; 150], |_| {}).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn truncated_future_line_is_an_error_and_color_zero_is_skipped() {
+        let full = vec!["2"; END_TURN as usize].join(" ");
+        assert_eq!(judge::parse_future_line(&format!("{}\n", full)).unwrap(), vec![2; END_TURN as usize]);
+        let err = judge::parse_future_line("1 2 3\n").unwrap_err();
+        assert!(err.contains("100") && err.contains("3"), "{}", err);
+        assert!(judge::parse_future_line(&format!("{} 1", full)).is_err());
+        assert!(judge::parse_future_line(&full.replacen('2', "x", 1)).is_err());
+        assert!(judge::parse_future_line("").is_err());
+        // 色0のターンは置かずに進む(デバッグビルドでもパニックしない)
+        let _guard = lock_globals();
+        let saved = FUTURE_CANDIES.lock().unwrap()[0];
+        FUTURE_CANDIES.lock().unwrap()[0] = 0;
+        let mut state = State::new();
+        state.update(1);
+        FUTURE_CANDIES.lock().unwrap()[0] = saved;
+        assert_eq!(state.empty_count(), H * W);
+    }
+
+    #[test]
+    fn convergence_trace_has_one_entry_per_checkpoint() {
+        let _guard = lock_globals();
+        let state = midgame(9, 70);
+        let checkpoints = [1, 2, 4, 8];
+        let trace = montecalro::convergence_trace(&state, &checkpoints, 3);
+        assert_eq!(trace.len(), checkpoints.len());
+        assert!(trace.iter().all(|action| LEGAL_ACTIONS.contains(action)));
+    }
+
+    #[test]
+    fn placed_turn_follows_the_candy_through_a_tilt() {
+        let _guard = lock_globals();
+        set_future(&[1; END_TURN as usize]).unwrap();
+        let mut state = State::new();
+        for _ in 0..3 {
+            state.update(1);
+            state.advance(Action::Right);
+        }
+        state.update(1);
+        let cells: Vec<(usize, usize)> = (0..H).flat_map(|y| (0..W).map(move |x| (y, x))).collect();
+        let (y, x) = cells.iter().copied().find(|&(y, x)| state.placed_turn(y, x) == Some(3)).unwrap();
+        let below = (y + 1..H).filter(|&ny| state.board[ny][x] != 0).count();
+        state.advance(Action::Back);
+        assert_eq!(state.placed_turn(H - 1 - below, x), Some(3));
+        assert_eq!(cells.iter().filter(|&&(y, x)| state.placed_turn(y, x) == Some(3)).count(), 1);
+        assert_eq!(state.placed_turn(0, 0), None);
+    }
+
+    #[test]
+    fn relaxed_upper_bound_bounds_sampled_rollouts() {
+        let _guard = lock_globals();
+        for seed in 0..10 {
+            let scenario = offline::Scenario::generate(seed);
+            set_future(scenario.future()).unwrap();
+            for start in [0, 10, 40, 70, 95] {
+                for policy in [greedy_action as fn(&State) -> Action, rulebase_action] {
+                    let mut state = State::new();
+                    let mut bound = f64::INFINITY;
+                    for (turn, &pt) in scenario.placements().iter().enumerate() {
+                        if turn == start {
+                            bound = state.relaxed_upper_bound(scenario.future());
+                        }
+                        state.update(pt);
+                        state.advance(policy(&state));
+                    }
+                    assert!(state.get_score() <= bound, "seed {} start {}: {} > {}", seed, start, state.get_score(), bound);
+                }
             }
         }
+        // 壁で仕切られた領域の間では合流できない
+        let mut state = board(&["1", "", "", "", "", "", "", "", "", "....1"], 2);
+        for y in 0..H {
+            state.set_wall(y, 2, true);
+        }
+        let future = [1; END_TURN as usize];
+        assert_eq!(board(&["1", "", "", "", "", "", "", "", "", "....1"], 2).relaxed_upper_bound(&future), state.remaining_optimal_score(&future));
+        assert_eq!(state.relaxed_upper_bound(&future), 1. + 99. * 99.);
+    }
+
+    #[test]
+    fn better_of_returns_the_higher_scoring_board() {
+        let grouped = board(&["11", "11"], 4);
+        let scattered = board(&["1.1", "...", "1.1"], 4);
+        assert!(std::ptr::eq(better_of(&grouped, &scattered), &grouped));
+        assert!(std::ptr::eq(better_of(&scattered, &grouped), &grouped));
+        let twin = grouped.clone();
+        assert!(std::ptr::eq(better_of(&grouped, &twin), &grouped));
     }
 
-    let mut source = LineSource::new(BufReader::new(std::io::stdin()));
-    input! {
-        from &mut source,
-        future: [u8; END_TURN],
+    #[test]
+    fn features_have_stable_length_and_empty_count() {
+        let _guard = lock_globals();
+        for state in [State::new(), board(&["12", "3"], 3), midgame(6, 45), midgame(6, END_TURN - 1)] {
+            let features = state.features();
+            assert_eq!(features.len(), FEATURE_LEN);
+            assert_eq!(features[COLORS], state.empty_count() as f64);
+        }
     }
-    {
-        let mut candies = FUTURE_CANDIES.lock().unwrap();
-        for (t, &f) in future.iter().enumerate() {
-            candies[t] = f;
+
+    #[test]
+    fn three_recorded_turns_give_three_histogram_entries() {
+        let mut time_keeper = time_keeper::TimeKeeper::new(300, 3);
+        for turn in 0..3 {
+            time_keeper.set_turn(turn);
+            time_keeper.record_turn_time();
         }
+        assert_eq!(time_keeper.turn_time_histogram().len(), 3);
+    }
+
+    #[test]
+    fn identical_strategies_tie_in_tournament() {
+        let _guard = lock_globals();
+        let strategies: Vec<(&str, Box<dyn Solver>)> = vec![("greedy a", Box::new(GreedySolver)), ("greedy b", Box::new(GreedySolver))];
+        let leaderboard = offline::tournament(&strategies, &[1, 2, 3], 100);
+        assert_eq!(leaderboard.len(), 2);
+        assert_eq!(leaderboard[0].1, leaderboard[1].1);
     }
 
-    let mut state = State::new();
-    let mut time_keeper = TimeKeeper::new(1950, END_TURN);
+    #[test]
+    fn forced_action_when_only_left_moves() {
+        // 右端の列が埋まっているので、上下にも右にも動かない
+        let state = board(&[".........1"; 10], 10);
+        assert_eq!(state.effective_actions(), vec![Action::Left]);
+        assert_eq!(state.forced_action(), Some(Action::Left));
+        assert_eq!(board(&["1"], 1).forced_action(), None);
+    }
+
+    #[test]
+    fn playout_variance_is_nonnegative_and_zero_when_terminal() {
+        let _guard = lock_globals();
+        let mut state = midgame(10, 80);
+        state.advance(rulebase_action(&state));
+        assert!(montecalro::playout_variance(&state, &montecalro::RandomPolicy, 20, 1) >= 0.);
+        assert!(montecalro::playout_variance(&state, &montecalro::RuleBasePolicy, 20, 1) >= 0.);
+        let terminal = midgame(10, END_TURN);
+        assert!(terminal.is_done());
+        assert_eq!(montecalro::playout_variance(&terminal, &montecalro::RuleBasePolicy, 20, 1), 0.);
+    }
 
-    for turn in 0..END_TURN {
-        time_keeper.set_turn(turn);
-        input! {
-            from &mut source,
-            pt: i64,
+    #[test]
+    fn action_table_lists_every_action_with_its_numbers() {
+        let mut stats = montecalro::MonteCarloStats::new();
+        for (d, value) in [(0, 10.), (0, 14.), (1, 3.), (2, 7.5), (3, 100.)] {
+            stats.push(d, value);
         }
-        state.update(pt);
-        let action = montecalro::primitive_monteralro(&time_keeper, &state);
-        // let action = rulebase_action(&state);
-        println!("{}", action_to_char(action));
-        std::io::stdout().flush().unwrap();
-        state.advance(action);
+        let table = montecalro::format_action_table(&stats);
+        let rows: Vec<&str> = table.lines().skip(1).collect();
+        assert_eq!(rows.len(), 4);
+        for ((row, label), (count, mean)) in rows.iter().zip(["F", "B", "L", "R"]).zip(stats.action_count().into_iter().zip(stats.action_mean())) {
+            let columns: Vec<&str> = row.split_whitespace().collect();
+            assert_eq!(columns[0], label);
+            assert_eq!(columns[1], count.to_string());
+            assert_eq!(columns[2], format!("{:.2}", mean));
+        }
+    }
+
+    #[test]
+    fn regret_is_zero_for_the_oracle_best_action() {
+        let _guard = lock_globals();
+        let state = midgame(11, END_TURN - 3);
+        let regrets = LEGAL_ACTIONS.map(|action| montecalro::estimated_regret(&state, action, 30, 2));
+        assert!(regrets.iter().all(|&regret| regret >= 0.), "{:?}", regrets);
+        let best = (0..4).fold(0, |best, d| if regrets[d] < regrets[best] { d } else { best });
+        assert_eq!(regrets[best], 0.);
+    }
+
+    #[test]
+    fn simulation_table_dump_and_load_roundtrip() {
+        let _guard = lock_globals();
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("simulation_table_{}_a.txt", std::process::id()));
+        let second = dir.join(format!("simulation_table_{}_b.txt", std::process::id()));
+        let before = RANDOM_FOR_SIMULATION.lock().unwrap().clone();
+        dump_simulation_table(&first).unwrap();
+        load_simulation_table(&first).unwrap();
+        dump_simulation_table(&second).unwrap();
+        assert_eq!(*RANDOM_FOR_SIMULATION.lock().unwrap(), before);
+        assert_eq!(std::fs::read(&first).unwrap(), std::fs::read(&second).unwrap());
+        std::fs::remove_file(first).unwrap();
+        std::fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    fn score_after_swap_matches_manual_swap() {
+        let state = board(&["112", "21", "2"], 6);
+        let mut manual = state.clone();
+        let (a, b) = ((0, 2), (1, 1));
+        manual.board[a.0][a.1] = state.board[b.0][b.1];
+        manual.board[b.0][b.1] = state.board[a.0][a.1];
+        assert_eq!(state.score_after_swap(a, b), manual.get_score());
+        assert_ne!(state.score_after_swap(a, b), state.get_score());
+        assert_eq!(state.board[0][2], 2);
+    }
+
+    #[test]
+    fn two_separated_pockets_are_two_regions() {
+        let mut rows = vec!["1111111111"; H];
+        rows[0] = "..11111111";
+        rows[H - 1] = "11111111.1";
+        let regions = board(&rows, 97).empty_regions();
+        assert_eq!(regions, vec![vec![(0, 0), (0, 1)], vec![(H - 1, 8)]]);
+    }
+
+    #[test]
+    fn profile_phases_fit_in_one_turn() {
+        // 累計時間はプロセス全体で共有するので、`profiling`featureを有効にするときは`--test-threads=1`で実行する
+        let _guard = lock_globals();
+        let state = midgame(12, 50);
+        profile::reset();
+        let start = std::time::Instant::now();
+        let time_keeper = time_keeper::TimeKeeper::new(30, 1);
+        let action = montecalro::primitive_monteralro(&time_keeper, &state, montecalro::TieBreak::default());
+        let mut next = state.clone();
+        next.advance(action);
+        let turn_time = start.elapsed();
+        let report = profile::report();
+        assert!(report.total() <= turn_time, "{:?} > {:?}", report, turn_time);
+        #[cfg(feature = "profiling")]
+        assert!(report.advance > std::time::Duration::ZERO && report.placement > std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn depth2_expectimax_agrees_with_exhaustive_endgame() {
+        let _guard = lock_globals();
+        for seed in 0..5 {
+            let state = midgame(seed, END_TURN - 2);
+            assert_eq!(state.remaining_turns(), 2);
+            let future = offline::Scenario::generate(seed).future().to_vec();
+            assert_eq!(Some(expectimax::expectimax_depth2(&state, &future)), expectimax::exact_endgame_action(&state, 2), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn regression_corpus() {
+        let _guard = lock_globals();
+        offline::check_regressions().unwrap();
+    }
+
+    #[test]
+    fn flush_every_five_writes_the_same_bytes_as_each_turn() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(13);
+        set_future(scenario.future()).unwrap();
+        let mut actions = vec![];
+        let mut state = State::new();
+        for &pt in scenario.placements() {
+            state.update(pt);
+            let action = rulebase_action(&state);
+            actions.push(action);
+            state.advance(action);
+        }
+        let write_all = |policy: OutputPolicy| {
+            let mut writer = ActionWriter::with_policy(std::io::BufWriter::new(Vec::new()), policy);
+            for &action in &actions {
+                writer.write_action(action).unwrap();
+            }
+            writer.finish().unwrap().into_inner().unwrap()
+        };
+        let each_turn = write_all(OutputPolicy::FlushEachTurn);
+        assert_eq!(each_turn.len(), 2 * END_TURN as usize);
+        assert_eq!(write_all(OutputPolicy::FlushEvery(5)), each_turn);
+    }
+
+    #[test]
+    fn score_by_color_attributes_big_group_to_its_color() {
+        let state = board(&["1111.2", "1111..", "1111.2", "", "2.2.2"], 17);
+        let by_color = state.score_by_color();
+        assert_eq!(by_color[1], 144.);
+        assert_eq!(by_color[2], 5.);
+        assert_eq!(by_color.iter().sum::<f64>(), state.get_score());
+    }
+
+    #[test]
+    fn pessimistic_playouts_score_below_random_placement() {
+        use placement::RandomPlacement;
+        let _guard = lock_globals();
+        for seed in 0..8 {
+            for turns in [5, 30, 60, 90] {
+                let state = midgame(seed, turns);
+                let mut rng = StdRng::seed_from_u64(seed);
+                for action in state.effective_actions() {
+                    let mut after = state.clone();
+                    after.advance(action);
+                    let rounds = 8;
+                    let pessimistic = (0..rounds)
+                        .map(|_| montecalro::pessimistic_playout(&after, montecalro::PESSIMISTIC_CANDIDATES, &mut rng))
+                        .sum::<f64>()
+                        / rounds as f64;
+                    let random = (0..32).map(|_| montecalro::playout_with_model(&mut after.clone(), &RandomPlacement, &mut rng)).sum::<f64>() / 32.;
+                    assert!(pessimistic <= random, "seed {} turn {} {:?}: {} > {}", seed, turns, action, pessimistic, random);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn three_forward_tilts_suggest_a_horizontal_action() {
+        let state = board(&["1.1..2"], 3);
+        let suggestion = state.suggest_direction_change(&[Action::Forward; 3]).unwrap();
+        assert!(!suggestion.is_vertical(), "{:?}", suggestion);
+        assert_eq!(suggestion, Action::Left);
+        assert_eq!(state.suggest_direction_change(&[Action::Forward, Action::Left, Action::Forward]), None);
+    }
+
+    #[test]
+    fn optimal_board_has_zero_heuristic() {
+        let counts = [0, 3, 3, 0];
+        assert_eq!(board(&["111", "222"], 6).heuristic_to_optimal(&counts), 0.);
+        assert_eq!(board(&["1.11", "222"], 6).heuristic_to_optimal(&counts), 1.);
+        assert_eq!(board(&["11", "222"], 5).heuristic_to_optimal(&counts), f64::INFINITY);
+    }
+
+    #[test]
+    fn heuristic_needs_two_tilts_for_diagonal_pieces() {
+        // 1は行にも列にも隙間があるので、どちらに1回傾けてもつながらない
+        let state = board(&["1..", "...", "..1"], 2);
+        let counts = state.color_counts();
+        assert_eq!(state.heuristic_to_optimal(&counts), 2.);
+        let mut best_after_one = f64::INFINITY;
+        for &action in LEGAL_ACTIONS.iter() {
+            let mut next = state.clone();
+            next.advance(action);
+            assert!(next.get_score() < max_possible_score(&counts));
+            best_after_one = best_after_one.min(next.heuristic_to_optimal(&counts));
+        }
+        // 下界なので、1回傾けた後は1以上で、実際に2回でまとまる
+        assert_eq!(best_after_one, 1.);
+        let mut next = state.clone();
+        next.advance(Action::Left);
+        next.advance(Action::Forward);
+        assert_eq!(next.heuristic_to_optimal(&counts), 0.);
+        // 同じ列にあれば上下に1回傾けるとつながりうるので1
+        assert_eq!(board(&["1..", "...", "1.."], 2).heuristic_to_optimal(&counts), 1.);
+    }
+
+    #[test]
+    fn slow_judge_game_finishes_within_budget_and_margin() {
+        let _guard = lock_globals();
+        let scenario = offline::Scenario::generate(15);
+        // 100ターン分の遅延(200ms)は持ち時間の中で吸収される
+        let elapsed = judge::check_slow_judge(scenario.future(), scenario.placements().to_vec(), 2, 500, 150).unwrap();
+        assert!(elapsed >= std::time::Duration::from_millis(200), "{:?}", elapsed);
     }
 }